@@ -0,0 +1,412 @@
+//! CPU reference implementations of the BSDFs in shaders.wgsl, used by the checks in validate.rs
+//! and, for the principled BSDF, by the unit tests below.
+//! All directions point away from the surface.
+
+use {
+    crate::{
+        math::Vec4,
+        sampling::{self, Frame, Rng},
+    },
+    std::f32::consts::PI,
+};
+
+/// A direction sampled from a BSDF together with its throughput weight (BSDF * cos / pdf) and the
+/// solid angle density it was sampled with.
+pub struct BsdfSample {
+    pub direction: Vec4,
+    pub weight: f32,
+    pub pdf: f32,
+}
+
+pub fn lambertian_eval(albedo: f32, normal: &Vec4, wi: &Vec4) -> f32 {
+    if normal.dot(wi) > 0. {
+        albedo / PI
+    } else {
+        0.
+    }
+}
+
+pub fn lambertian_pdf(normal: &Vec4, wi: &Vec4) -> f32 {
+    normal.dot(wi).max(0.) / PI
+}
+
+/// Same as the `MATERIAL_LAMBERTIAN` branch of `scatter_ray`.
+pub fn lambertian_sample(albedo: f32, normal: &Vec4, rng: &mut Rng) -> BsdfSample {
+    let direction = Frame::new(*normal).to_world(sampling::sample_cosine_hemisphere(rng));
+    let cos_theta = normal.dot(&direction).max(0.);
+    let pdf = lambertian_pdf(normal, &direction);
+    let weight = if pdf > 0. {
+        lambertian_eval(albedo, normal, &direction) * cos_theta / pdf
+    } else {
+        0.
+    };
+    BsdfSample { direction, weight, pdf }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::test_util::{assert_estimate, estimate},
+    };
+
+    // Same as the constants in shaders.wgsl.
+    const MIN_GGX_ALPHA: f32 = 1e-3;
+    const CLEARCOAT_ALPHA: f32 = 0.05;
+
+    /// The principled BSDF of `MATERIAL_PRINCIPLED` materials with a grey base colour, so that its
+    /// values are scalars like those of the other reference BSDFs.
+    ///
+    /// Directions are given in the local frame of the surface, where the normal is +z and `wo` is
+    /// above the surface. `eta` is the refractive index below the surface relative to the one above
+    /// it, as returned by `principled_eta` in shaders.wgsl.
+    #[derive(Debug, Copy, Clone)]
+    struct Principled {
+        base_colour: f32,
+        metallic: f32,
+        roughness: f32,
+        specular: f32,
+        clearcoat: f32,
+        transmission: f32,
+        ior: f32,
+    }
+
+    impl Principled {
+        /// Same as `principled_eval` in shaders.wgsl.
+        fn eval(&self, wo: &Vec4, wi: &Vec4, eta: f32) -> f32 {
+            let alpha = self.alpha();
+            let dielectric = 1. - self.metallic;
+            if wo.z() <= 0. || wi.z() == 0. {
+                return 0.;
+            }
+            if wi.z() < 0. {
+                let Some(h) = refraction_half_vector(wo, wi, eta) else {
+                    return 0.;
+                };
+                let (wo_h, wi_h) = (wo.dot(&h), wi.dot(&h));
+                let denominator = wi_h + wo_h / eta;
+                let transmittance = 1. - fresnel_dielectric(wo_h, eta);
+                let microfacets = ggx_d(&h, alpha) * ggx_g2(wo, wi, alpha)
+                    * (wi_h * wo_h / (wi.z() * wo.z() * denominator * denominator)).abs();
+                let weight = dielectric * self.transmission * self.base_colour * transmittance;
+                return weight * microfacets / (eta * eta);
+            }
+
+            let h = (*wo + *wi).normalized();
+            let wo_h = wo.dot(&h);
+            let specular = ggx_d(&h, alpha) * ggx_g2(wo, wi, alpha) / (4. * wo.z() * wi.z());
+            let clearcoat = ggx_d(&h, CLEARCOAT_ALPHA) * ggx_g2(wo, wi, CLEARCOAT_ALPHA)
+                / (4. * wo.z() * wi.z());
+            dielectric * (1. - self.transmission) * self.base_colour / PI
+                + self.specular_fresnel(wo_h) * specular
+                + dielectric * self.transmission * fresnel_dielectric(wo_h, eta) * specular
+                + 0.25 * self.clearcoat * schlick_fresnel(0.04, wo_h) * clearcoat
+        }
+
+        /// Same as `principled_pdf` in shaders.wgsl.
+        fn pdf(&self, wo: &Vec4, wi: &Vec4, eta: f32) -> f32 {
+            let alpha = self.alpha();
+            let [diffuse, specular, transmission, clearcoat] = self.lobe_probabilities(wo);
+            if wo.z() <= 0. || wi.z() == 0. {
+                return 0.;
+            }
+            if wi.z() < 0. {
+                let Some(h) = refraction_half_vector(wo, wi, eta) else {
+                    return 0.;
+                };
+                let (wo_h, wi_h) = (wo.dot(&h), wi.dot(&h));
+                let denominator = wi_h + wo_h / eta;
+                let visible_normal_pdf = ggx_g1(wo, alpha) * ggx_d(&h, alpha) * wo_h / wo.z();
+                let transmittance = 1. - fresnel_dielectric(wo_h, eta);
+                return transmission * transmittance * visible_normal_pdf * wi_h.abs()
+                    / (denominator * denominator);
+            }
+
+            let h = (*wo + *wi).normalized();
+            diffuse * wi.z().max(0.) / PI
+                + specular * ggx_pdf(wo, wi, alpha)
+                + transmission * fresnel_dielectric(wo.dot(&h), eta) * ggx_pdf(wo, wi, alpha)
+                + clearcoat * ggx_pdf(wo, wi, CLEARCOAT_ALPHA)
+        }
+
+        /// Same as `sample_principled` in shaders.wgsl.
+        fn sample(&self, wo: &Vec4, eta: f32, rng: &mut Rng) -> BsdfSample {
+            let alpha = self.alpha();
+            let [diffuse, specular, transmission, _] = self.lobe_probabilities(wo);
+            let u = rng.next_f32();
+            let mut transmitted = false;
+            let direction = if u < diffuse {
+                sampling::sample_cosine_hemisphere(rng)
+            } else if u < diffuse + specular {
+                reflect(&-*wo, &sample_ggx_vndf(wo, alpha, rng))
+            } else if u < diffuse + specular + transmission {
+                let h = sample_ggx_vndf(wo, alpha, rng);
+                if rng.next_f32() < fresnel_dielectric(wo.dot(&h), eta) {
+                    reflect(&-*wo, &h)
+                } else {
+                    transmitted = true;
+                    refract(&-*wo, &h, 1. / eta)
+                }
+            } else {
+                reflect(&-*wo, &sample_ggx_vndf(wo, CLEARCOAT_ALPHA, rng))
+            };
+
+            let wrong_side = if transmitted { direction.z() >= 0. } else { direction.z() <= 0. };
+            let pdf = self.pdf(wo, &direction, eta);
+            if wrong_side || pdf <= 0. {
+                return BsdfSample { direction, weight: 0., pdf: 0. };
+            }
+            let weight = self.eval(wo, &direction, eta) * direction.z().abs() / pdf;
+            BsdfSample { direction, weight, pdf }
+        }
+
+        /// Same as `principled_eta` in shaders.wgsl.
+        fn eta(&self, front_face: bool) -> f32 {
+            if front_face {
+                self.ior
+            } else {
+                1. / self.ior
+            }
+        }
+
+        fn alpha(&self) -> f32 {
+            (self.roughness * self.roughness).max(MIN_GGX_ALPHA)
+        }
+
+        fn specular_fresnel(&self, cosine: f32) -> f32 {
+            let dielectric = (1. - self.metallic) * (1. - self.transmission);
+            dielectric * schlick_fresnel(0.08 * self.specular, cosine)
+                + self.metallic * schlick_fresnel(self.base_colour, cosine)
+        }
+
+        fn lobe_probabilities(&self, wo: &Vec4) -> [f32; 4] {
+            let lobes = [
+                (1. - self.metallic) * (1. - self.transmission) * self.base_colour,
+                self.specular_fresnel(wo.z()),
+                (1. - self.metallic) * self.transmission,
+                0.25 * self.clearcoat * schlick_fresnel(0.04, wo.z()),
+            ];
+            let total: f32 = lobes.iter().sum();
+            if total > 0. {
+                lobes.map(|p| p / total)
+            } else {
+                [0.; 4]
+            }
+        }
+    }
+
+    fn reflect(incident: &Vec4, normal: &Vec4) -> Vec4 {
+        *incident - *normal * (2. * normal.dot(incident))
+    }
+
+    // Same as the WGSL built-in, where `eta` is the ratio of the refractive index on the incident
+    // side to the one on the transmitted side.
+    fn refract(incident: &Vec4, normal: &Vec4, eta: f32) -> Vec4 {
+        let cos_i = normal.dot(incident);
+        let k = 1. - eta * eta * (1. - cos_i * cos_i);
+        if k < 0. {
+            return Vec4::zero();
+        }
+        *incident * eta - *normal * (eta * cos_i + k.sqrt())
+    }
+
+    fn schlick_fresnel(f0: f32, cosine: f32) -> f32 {
+        f0 + (1. - f0) * (1. - cosine.clamp(0., 1.)).powi(5)
+    }
+
+    fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+        let sin2_t = (1. - cos_i * cos_i) / (eta * eta);
+        if sin2_t >= 1. {
+            return 1.;
+        }
+        let cos_t = (1. - sin2_t).sqrt();
+        let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+        let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+        0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+    }
+
+    fn refraction_half_vector(wo: &Vec4, wi: &Vec4, eta: f32) -> Option<Vec4> {
+        let h = *wo + *wi * eta;
+        if h.length_squared() == 0. {
+            return None;
+        }
+        let h = h.normalized() * h.z().signum();
+        (wo.dot(&h) > 0. && wi.dot(&h) < 0.).then_some(h)
+    }
+
+    fn ggx_d(h: &Vec4, alpha: f32) -> f32 {
+        let a2 = alpha * alpha;
+        let d = (h.x() * h.x() + h.y() * h.y()) / a2 + h.z() * h.z();
+        1. / (PI * a2 * d * d)
+    }
+
+    fn ggx_lambda(w: &Vec4, alpha: f32) -> f32 {
+        let tan2_theta = (1. - w.z() * w.z()).max(0.) / (w.z() * w.z());
+        0.5 * ((1. + alpha * alpha * tan2_theta).sqrt() - 1.)
+    }
+
+    fn ggx_g1(w: &Vec4, alpha: f32) -> f32 {
+        1. / (1. + ggx_lambda(w, alpha))
+    }
+
+    fn ggx_g2(wo: &Vec4, wi: &Vec4, alpha: f32) -> f32 {
+        1. / (1. + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha))
+    }
+
+    fn ggx_pdf(wo: &Vec4, wi: &Vec4, alpha: f32) -> f32 {
+        let h = (*wo + *wi).normalized();
+        ggx_g1(wo, alpha) * ggx_d(&h, alpha) / (4. * wo.z())
+    }
+
+    // Same as `sample_ggx_vndf` in shaders.wgsl.
+    fn sample_ggx_vndf(wo: &Vec4, alpha: f32, rng: &mut Rng) -> Vec4 {
+        let v = Vec4::new(alpha * wo.x(), alpha * wo.y(), wo.z()).normalized();
+        let len2 = v.x() * v.x() + v.y() * v.y();
+        let t1 = if len2 > 0. {
+            Vec4::new(-v.y(), v.x(), 0.) / len2.sqrt()
+        } else {
+            Vec4::new(1., 0., 0.)
+        };
+        let t2 = v.cross(&t1);
+
+        let r = rng.next_f32().sqrt();
+        let phi = 2. * PI * rng.next_f32();
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1. + v.z());
+        let p2 = (1. - s) * (1. - p1 * p1).max(0.).sqrt() + s * r * phi.sin();
+        let n = t1 * p1 + t2 * p2 + v * (1. - p1 * p1 - p2 * p2).max(0.).sqrt();
+        Vec4::new(alpha * n.x(), alpha * n.y(), n.z().max(0.)).normalized()
+    }
+
+    fn random_principled(rng: &mut Rng) -> Principled {
+        // Mostly pure dielectrics and metals, which is how the parameters are normally used.
+        let mut pick = |values: [f32; 2]| match rng.next_u32() % 3 {
+            0 => values[0],
+            1 => values[1],
+            _ => rng.next_f32(),
+        };
+        Principled {
+            base_colour: pick([0.1, 1.]),
+            metallic: pick([0., 1.]),
+            roughness: pick([0.05, 1.]),
+            specular: pick([0., 1.]),
+            clearcoat: pick([0., 1.]),
+            transmission: pick([0., 1.]),
+            ior: 1. + pick([0.01, 1.5]),
+        }
+    }
+
+    // A random direction in the local frame of a surface, above it.
+    fn random_outgoing_direction(rng: &mut Rng) -> Vec4 {
+        let d = sampling::sample_sphere(rng);
+        Vec4::new(d.x(), d.y(), d.z().abs().max(1e-3)).normalized()
+    }
+
+    // Swapping the directions of light must give the same reflected radiance. Transmitted radiance
+    // is compressed into the smaller solid angle of the denser medium, so the BSDF is only
+    // symmetric when scaled by the squared refractive index on the side of the incoming light
+    // (Veach, "Robust Monte Carlo Methods for Light Transport Simulation", section 5.2).
+    #[test]
+    fn principled_is_reciprocal() {
+        const MATERIALS: u32 = 200;
+        const PAIRS_PER_MATERIAL: u32 = 500;
+
+        let mut rng = Rng::new(4);
+        for _ in 0..MATERIALS {
+            let material = random_principled(&mut rng);
+            for _ in 0..PAIRS_PER_MATERIAL {
+                let eta = material.eta(rng.next_u32().is_multiple_of(2));
+                let wo = random_outgoing_direction(&mut rng);
+                let wi = sampling::sample_sphere(&mut rng);
+                let (f, f_reversed) = if wi.z() > 0. {
+                    (material.eval(&wo, &wi, eta), material.eval(&wi, &wo, eta))
+                } else {
+                    // Close to grazing angles on the microfacet, the Fresnel term on one side is
+                    // computed from a cosine that rounding has made inaccurate.
+                    let h = (wo + wi * eta).normalized();
+                    if wo.dot(&h).abs().min(wi.dot(&h).abs()) < 0.02 {
+                        continue;
+                    }
+                    // Seen from the other side, the normal and with it both directions are flipped.
+                    (material.eval(&wo, &wi, eta) * eta * eta, material.eval(&-wi, &-wo, 1. / eta))
+                };
+                let tolerance = 1e-3 * f.abs().max(f_reversed.abs()) + 1e-6;
+                assert!(
+                    f.is_finite() && f >= 0. && (f - f_reversed).abs() <= tolerance,
+                    "f({:?}, {:?}) = {} but the reverse is {} for {:?} with eta {}",
+                    wo,
+                    wi,
+                    f,
+                    f_reversed,
+                    material,
+                    eta
+                );
+            }
+        }
+    }
+
+    // The density reported by sampling must be the density returned by `pdf`, and `pdf` must be the
+    // density that directions are actually sampled with: it integrates to the fraction of samples
+    // that are not discarded, and importance sampling estimates the same reflectance as uniform
+    // sampling.
+    #[test]
+    fn principled_sampling_matches_pdf() {
+        const MATERIALS: u32 = 12;
+        const SAMPLES: u32 = 200_000;
+
+        let mut rng = Rng::new(5);
+        for i in 0..MATERIALS {
+            // Narrow lobes make the uniform estimates too noisy to be useful. Transmission through
+            // an interface with eta close to 1 also stays close to the opposite of `wo`.
+            let material = Principled {
+                roughness: rng.range(0.3, 1.),
+                ior: rng.range(1.3, 2.5),
+                ..random_principled(&mut rng)
+            };
+            let eta = material.eta(i % 2 == 0);
+            let wo = random_outgoing_direction(&mut rng);
+
+            let mut valid = 0;
+            let mut sample_rng = Rng::new(6 + i);
+            let importance_sampled = estimate(SAMPLES, || {
+                let sample = material.sample(&wo, eta, &mut sample_rng);
+                valid += (sample.pdf > 0.) as u32;
+                sample.weight
+            });
+            for _ in 0..1000 {
+                let sample = material.sample(&wo, eta, &mut sample_rng);
+                let pdf = material.pdf(&wo, &sample.direction, eta);
+                assert!(
+                    sample.pdf <= 0. || (sample.pdf - pdf).abs() <= 1e-4 * pdf,
+                    "sampled with pdf {} but the pdf is {} for {:?}",
+                    sample.pdf,
+                    pdf,
+                    material
+                );
+            }
+
+            let uniform = estimate(SAMPLES, || {
+                let wi = sampling::sample_sphere(&mut rng);
+                material.eval(&wo, &wi, eta) * wi.z().abs() * 4. * PI
+            });
+            let pdf_integral = estimate(SAMPLES, || {
+                material.pdf(&wo, &sampling::sample_sphere(&mut rng), eta) * 4. * PI
+            });
+
+            let name = format!("{:?} with eta {}", material, eta);
+            assert_estimate(
+                &format!("pdf integral of {}", name),
+                pdf_integral,
+                valid as f32 / SAMPLES as f32,
+            );
+            // Both estimates are noisy, so compare their difference against the combined error.
+            let difference = importance_sampled.0 - uniform.0;
+            let error = importance_sampled.1.hypot(uniform.1);
+            assert_estimate(
+                &format!("importance sampled - uniform reflectance of {}", name),
+                (difference, error),
+                0.,
+            );
+        }
+    }
+}
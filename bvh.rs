@@ -0,0 +1,353 @@
+use {
+    crate::math::{Ray, Vec4},
+    bytemuck::{Pod, Zeroable},
+};
+
+// The GPU traversal uses a fixed size stack (see `BVH_STACK_SIZE` in shaders.wgsl), which bounds
+// the depth of the tree. Nodes at this depth are always turned into leaves.
+const MAX_DEPTH: usize = 30;
+
+// Number of buckets that primitive centroids are sorted into when evaluating the SAH.
+const BIN_COUNT: usize = 16;
+
+// Relative cost of visiting a node compared to intersecting a primitive.
+const TRAVERSAL_COST: f32 = 1.;
+
+/// An axis-aligned bounding box.
+#[derive(Debug, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec4,
+    pub max: Vec4,
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec4::all(f32::INFINITY),
+            max: Vec4::all(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Vec4]) -> Aabb {
+        points.iter().fold(Aabb::empty(), |aabb, p| aabb.grow(p))
+    }
+
+    pub fn grow(&self, p: &Vec4) -> Aabb {
+        Aabb {
+            min: self.min.min(p),
+            max: self.max.max(p),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec4 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        if d.x() < 0. {
+            return 0.;
+        }
+        2. * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+
+    /// Returns the distance at which the ray enters the box, if it does so before `t_max`. This
+    /// is the same slab test as `intersect_aabb` in shaders.wgsl.
+    pub fn intersect(&self, ray: &Ray, t_max: f32) -> Option<f32> {
+        let mut t_near = f32::NEG_INFINITY;
+        let mut t_far = f32::INFINITY;
+        for axis in 0..3 {
+            let inv_dir = 1. / ray.direction[axis];
+            let t0 = (self.min[axis] - ray.origin[axis]) * inv_dir;
+            let t1 = (self.max[axis] - ray.origin[axis]) * inv_dir;
+            t_near = t_near.max(t0.min(t1));
+            t_far = t_far.min(t0.max(t1));
+        }
+        (t_near <= t_far && t_far > 0. && t_near < t_max).then_some(t_near)
+    }
+}
+
+/// GPU representation of a BVH node, laid out to match `struct BvhNode` in shaders.wgsl.
+///
+/// For interior nodes `primitive_count` is 0 and `left_or_first` is the index of the left child;
+/// the right child always follows it. For leaves `left_or_first` is the index of the first entry
+/// in `Bvh::primitives` that belongs to the leaf.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct BvhNode {
+    aabb_min: [f32; 3],
+    left_or_first: u32,
+    aabb_max: [f32; 3],
+    primitive_count: u32,
+}
+
+impl BvhNode {
+    fn aabb(&self) -> Aabb {
+        Aabb {
+            min: self.aabb_min.into(),
+            max: self.aabb_max.into(),
+        }
+    }
+}
+
+/// A bounding volume hierarchy built with the surface area heuristic and flattened so that it
+/// can be uploaded to the GPU as is.
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    /// Primitive ids, ordered so that each leaf refers to a contiguous range.
+    pub primitives: Vec<u32>,
+}
+
+struct BuildPrimitive {
+    id: u32,
+    aabb: Aabb,
+    centroid: Vec4,
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    aabb: Aabb,
+    count: usize,
+}
+
+impl Bvh {
+    /// Builds a BVH over primitives given as `(id, bounds)` pairs. The ids are opaque to the BVH
+    /// and returned from traversal as they are.
+    pub fn build(primitives: impl IntoIterator<Item = (u32, Aabb)>) -> Bvh {
+        let mut primitives: Vec<BuildPrimitive> = primitives
+            .into_iter()
+            .map(|(id, aabb)| BuildPrimitive { id, aabb, centroid: aabb.centroid() })
+            .collect();
+        let mut nodes = vec![BvhNode::zeroed()];
+        build_node(&mut nodes, &mut primitives, 0, 0, 0);
+        Bvh {
+            nodes,
+            primitives: primitives.iter().map(|p| p.id).collect(),
+        }
+    }
+
+    /// Finds the closest primitive along `ray`. `intersect` is called with a primitive id and
+    /// returns the distance to the primitive if the ray hits it. This mirrors `closest_hit` in
+    /// shaders.wgsl.
+    pub fn closest_hit(
+        &self,
+        ray: &Ray,
+        mut intersect: impl FnMut(u32) -> Option<f32>,
+    ) -> Option<(u32, f32)> {
+        let mut closest: Option<(u32, f32)> = None;
+        let t_max = |closest: &Option<(u32, f32)>| closest.map_or(f32::MAX, |(_, t)| t);
+
+        let mut stack = Vec::with_capacity(MAX_DEPTH + 2);
+        if self.nodes[0].aabb().intersect(ray, f32::MAX).is_some() {
+            stack.push(0);
+        }
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index as usize];
+            if node.primitive_count > 0 {
+                let first = node.left_or_first as usize;
+                for &id in &self.primitives[first..first + node.primitive_count as usize] {
+                    if let Some(t) = intersect(id) {
+                        if t > 0. && t < t_max(&closest) {
+                            closest = Some((id, t));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            // Visit the nearer child first by pushing it last.
+            let left = node.left_or_first;
+            let right = left + 1;
+            let t_left = self.nodes[left as usize].aabb().intersect(ray, t_max(&closest));
+            let t_right = self.nodes[right as usize].aabb().intersect(ray, t_max(&closest));
+            match (t_left, t_right) {
+                (Some(t_left), Some(t_right)) if t_left < t_right => stack.extend([right, left]),
+                (Some(_), Some(_)) => stack.extend([left, right]),
+                (Some(_), None) => stack.push(left),
+                (None, Some(_)) => stack.push(right),
+                (None, None) => (),
+            }
+        }
+        closest
+    }
+}
+
+fn build_node(
+    nodes: &mut Vec<BvhNode>,
+    primitives: &mut [BuildPrimitive],
+    node_index: usize,
+    first: usize,
+    depth: usize,
+) {
+    let aabb = primitives.iter().fold(Aabb::empty(), |aabb, p| aabb.union(&p.aabb));
+    nodes[node_index] = BvhNode {
+        aabb_min: aabb.min.to_array(),
+        left_or_first: first as u32,
+        aabb_max: aabb.max.to_array(),
+        primitive_count: primitives.len() as u32,
+    };
+    if primitives.len() <= 1 || depth >= MAX_DEPTH {
+        return;
+    }
+
+    let Some(split) = find_sah_split(primitives, &aabb) else {
+        return;
+    };
+
+    // Partition the primitives around the split plane.
+    let mut mid = 0;
+    for i in 0..primitives.len() {
+        if split.bin(&primitives[i]) < split.bin_index {
+            primitives.swap(i, mid);
+            mid += 1;
+        }
+    }
+    if mid == 0 || mid == primitives.len() {
+        return;
+    }
+
+    let left = nodes.len();
+    nodes.push(BvhNode::zeroed());
+    nodes.push(BvhNode::zeroed());
+    nodes[node_index].left_or_first = left as u32;
+    nodes[node_index].primitive_count = 0;
+
+    let (left_primitives, right_primitives) = primitives.split_at_mut(mid);
+    build_node(nodes, left_primitives, left, first, depth + 1);
+    build_node(nodes, right_primitives, left + 1, first + mid, depth + 1);
+}
+
+// A split plane between two bins along one axis. Primitives whose centroid falls into a bin below
+// `bin_index` go to the left child.
+#[derive(Copy, Clone)]
+struct Split {
+    axis: usize,
+    lo: f32,
+    scale: f32,
+    bin_index: usize,
+}
+
+impl Split {
+    fn bin(&self, primitive: &BuildPrimitive) -> usize {
+        (((primitive.centroid[self.axis] - self.lo) * self.scale) as usize).min(BIN_COUNT - 1)
+    }
+}
+
+// Returns the binned split plane with the lowest SAH cost, or `None` if keeping the primitives in
+// a leaf is cheaper.
+fn find_sah_split(primitives: &[BuildPrimitive], aabb: &Aabb) -> Option<Split> {
+    let centroid_bounds = primitives
+        .iter()
+        .fold(Aabb::empty(), |aabb, p| aabb.grow(&p.centroid));
+
+    let mut best: Option<Split> = None;
+    let mut best_cost = primitives.len() as f32;
+    for axis in 0..3 {
+        let lo = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - lo;
+        if extent <= 0. {
+            continue;
+        }
+
+        let mut split = Split { axis, lo, scale: BIN_COUNT as f32 / extent, bin_index: 0 };
+        let mut bins = [Bin { aabb: Aabb::empty(), count: 0 }; BIN_COUNT];
+        for p in primitives {
+            let bin = split.bin(p);
+            bins[bin].aabb = bins[bin].aabb.union(&p.aabb);
+            bins[bin].count += 1;
+        }
+
+        // Sweep from the right to get the cost of everything right of each split plane, then
+        // from the left to combine it with the left side.
+        let mut right_cost = [0.; BIN_COUNT];
+        let mut right = Bin { aabb: Aabb::empty(), count: 0 };
+        for i in (1..BIN_COUNT).rev() {
+            right.aabb = right.aabb.union(&bins[i].aabb);
+            right.count += bins[i].count;
+            right_cost[i] = right.aabb.surface_area() * right.count as f32;
+        }
+        let mut left = Bin { aabb: Aabb::empty(), count: 0 };
+        for i in 1..BIN_COUNT {
+            left.aabb = left.aabb.union(&bins[i - 1].aabb);
+            left.count += bins[i - 1].count;
+            if left.count == 0 || left.count == primitives.len() {
+                continue;
+            }
+            let cost = TRAVERSAL_COST
+                + (left.aabb.surface_area() * left.count as f32 + right_cost[i]) / aabb.surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                split.bin_index = i;
+                best = Some(split);
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::{
+            math::{Ray, Vec4},
+            sampling::{self, Rng},
+            scene::{Material, Scene, Sphere, Triangle, Vertex},
+            test_util::random_point,
+        },
+        bytemuck::Zeroable,
+    };
+
+    // A random soup of spheres and triangles of varying size.
+    fn random_scene(rng: &mut Rng, sphere_count: usize, triangle_count: usize) -> Scene {
+        let spheres = (0..sphere_count)
+            .map(|_| Sphere::new(random_point(rng, 10.), rng.range(0.05, 1.5), 0))
+            .collect();
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+        for i in 0..triangle_count as u32 {
+            let center = random_point(rng, 10.);
+            let size = rng.range(0.1, 3.);
+            for _ in 0..3 {
+                let position = center + random_point(rng, size);
+                vertices.push(Vertex::new(position, Vec4::new(0., 1., 0.)));
+            }
+            triangles.push(Triangle::new([3 * i, 3 * i + 1, 3 * i + 2], 0));
+        }
+        Scene::new(spheres, vec![Material::zeroed()], vertices, triangles)
+    }
+
+    #[test]
+    fn closest_hit_matches_brute_force() {
+        const SCENES: u32 = 20;
+        const RAYS_PER_SCENE: u32 = 2000;
+
+        let mut rng = Rng::new(1);
+        for scene_index in 0..SCENES {
+            let sphere_count = (rng.next_u32() % 100) as usize;
+            let triangle_count = (rng.next_u32() % 400) as usize + 1;
+            let scene = random_scene(&mut rng, sphere_count, triangle_count);
+            for _ in 0..RAYS_PER_SCENE {
+                let ray = Ray::new(random_point(&mut rng, 15.), sampling::sample_sphere(&mut rng));
+                let expected = scene.intersect_brute_force(&ray);
+                let actual = scene.intersect(&ray);
+                // Different primitives can be hit at the same distance, so only compare distances.
+                assert_eq!(
+                    actual.map(|(_, t)| t),
+                    expected.map(|(_, t)| t),
+                    "traversal found {:?} but brute force found {:?} for {:?} in scene {}",
+                    actual,
+                    expected,
+                    ray,
+                    scene_index
+                );
+            }
+        }
+    }
+}
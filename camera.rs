@@ -1,7 +1,15 @@
 use bytemuck::{Pod, Zeroable};
-use winit::{event::KeyEvent, keyboard::PhysicalKey};
+use serde::Deserialize;
+use std::{
+    f32::consts::{PI, TAU},
+    fmt,
+};
+use winit::{
+    event::{ElementState, KeyEvent},
+    keyboard::{KeyCode, PhysicalKey},
+};
 
-use crate::math::{Vec4};
+use crate::math::{Ray, Vec4};
 
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
@@ -10,11 +18,148 @@ pub struct CameraUniforms {
     u: Vec4,      
     v: Vec4,      
     w: Vec4,      
+    // Radius of the thin lens. Zero for a pinhole camera, where everything is in focus.
+    aperture_radius: f32,
+    // Distance along `w` of the plane in focus.
+    focus_distance: f32,
+    // Number of sides of a polygonal aperture, or zero for a round one.
+    aperture_blades: u32,
+    projection: u32,
+    // Vertical field of view in radians.
+    vfov: f32,
+    // Distance of the plane that orthographic views frame like perspective views do. Zooming
+    // changes it but focusing does not, so that focusing does not change the framing.
+    orthographic_distance: f32,
+    _padding: [u32; 2],
 }
 
+/// How the camera projects the scene onto the image. The values are those of the `PROJECTION_*`
+/// constants in shaders.wgsl.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[repr(u32)]
+pub enum Projection {
+    Perspective = 0,
+    /// Parallel rays, framing the plane through the point looked at like the perspective
+    /// projection does.
+    Orthographic = 1,
+    /// A 360 degree panorama, in the layout of an environment map when the image is twice as wide
+    /// as it is high.
+    Equirectangular = 2,
+    /// An equidistant fisheye with the field of view between the top and bottom edges.
+    Fisheye = 3,
+}
+
+impl Projection {
+    /// The projection after this one, wrapping around to the first.
+    pub fn next(self) -> Projection {
+        match self {
+            Projection::Perspective => Projection::Orthographic,
+            Projection::Orthographic => Projection::Equirectangular,
+            Projection::Equirectangular => Projection::Fisheye,
+            Projection::Fisheye => Projection::Perspective,
+        }
+    }
+
+    fn from_u32(value: u32) -> Projection {
+        match value {
+            1 => Projection::Orthographic,
+            2 => Projection::Equirectangular,
+            3 => Projection::Fisheye,
+            _ => Projection::Perspective,
+        }
+    }
+}
+
+impl fmt::Display for Projection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Projection::Perspective => "perspective",
+            Projection::Orthographic => "orthographic",
+            Projection::Equirectangular => "equirectangular",
+            Projection::Fisheye => "fisheye",
+        })
+    }
+}
+
+// Keeps the plane in focus in front of the lens.
+const MIN_FOCUS_DISTANCE: f32 = 1e-3;
+// Smallest aperture radius that the keyboard controls open the lens to, below which it closes.
+const MIN_APERTURE_RADIUS: f32 = 0.005;
+// Factor that the aperture radius and the focus distance change by per key press.
+const APERTURE_STEP: f32 = 1.25;
+const FOCUS_STEP: f32 = 1.1;
+// Most aperture blades that the keyboard controls cycle through.
+const MAX_APERTURE_BLADES: u32 = 8;
+/// Range of the vertical field of view in degrees. Projections onto a plane are limited further,
+/// see `MAX_PLANAR_HALF_VFOV`.
+pub const MIN_VFOV: f32 = 1.;
+pub const MAX_VFOV: f32 = 360.;
+// Same as the constant in shaders.wgsl.
+const MAX_PLANAR_HALF_VFOV: f32 = 1.5621;
+// Factor that the field of view changes by per key press.
+const VFOV_STEP: f32 = 1.1;
+/// Field of view of cameras that do not specify one, in degrees.
+pub const DEFAULT_VFOV: f32 = 90.;
+// Closest angles to the up axis that the camera can look at, since the image would spin around the
+// view direction at the poles.
+const MIN_PITCH: f32 = 0.01;
+const MAX_PITCH: f32 = PI - MIN_PITCH;
+// Factor that the speed of the camera is multiplied by while Shift is held.
+const SPRINT_FACTOR: f32 = 4.;
+
+/// The movement keys that are held down, so that the camera moves smoothly for as long as they are
+/// rather than by a step per key repeat.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Movement {
+    forward: bool,
+    backward: bool,
+    left: bool,
+    right: bool,
+    up: bool,
+    down: bool,
+    sprint: bool,
+}
+
+impl Movement {
+    /// Records presses and releases of the movement keys: `W` `A` `S` `D`, `Space` or `E` to rise,
+    /// `Ctrl` or `Q` to sink, and `Shift` to sprint. Returns whether the key is one of them.
+    pub fn handle_key(&mut self, key: &KeyEvent) -> bool {
+        let PhysicalKey::Code(code) = key.physical_key else {
+            return false;
+        };
+        let held = match code {
+            KeyCode::KeyW => &mut self.forward,
+            KeyCode::KeyS => &mut self.backward,
+            KeyCode::KeyA => &mut self.left,
+            KeyCode::KeyD => &mut self.right,
+            KeyCode::Space | KeyCode::KeyE => &mut self.up,
+            KeyCode::ControlLeft | KeyCode::ControlRight | KeyCode::KeyQ => &mut self.down,
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => &mut self.sprint,
+            _ => return false,
+        };
+        *held = key.state == ElementState::Pressed;
+        true
+    }
+
+    /// Releases all keys, for when the window loses the keyboard focus and misses the releases.
+    pub fn release_all(&mut self) {
+        *self = Movement::default();
+    }
+}
+
+/// The orientation is given by the yaw and pitch angles, from which the axes in the uniforms are
+/// derived.
 #[derive(Debug, Copy, Clone)]
 pub struct Camera {
-    uniforms: CameraUniforms, yaw : f32, pitch :f32,
+    uniforms: CameraUniforms,
+    // Angle of the view direction around `up`, see `horizontal_axes`.
+    yaw: f32,
+    // Angle of the view direction from `up`, in [MIN_PITCH, MAX_PITCH].
+    pitch: f32,
+    // Unit vector that the camera turns around, which also stays upright in the image.
+    up: Vec4,
+    // Whether turning the camera moves it around the point in focus instead of in place.
+    orbit: bool,
 }
 
 impl Camera {
@@ -22,56 +167,330 @@ impl Camera {
         &self.uniforms
     }
 
+    /// Moves the camera along the view direction. In orbit mode the camera stops short of the
+    /// point in focus, which stays where it is.
     pub fn zoom(&mut self, displacement: f32) {
+        let distance = self.uniforms.orthographic_distance - displacement;
+        self.uniforms.orthographic_distance = distance.max(MIN_FOCUS_DISTANCE);
+        if self.orbit {
+            let pivot = self.pivot();
+            let distance = (self.focus_distance() - displacement).max(MIN_FOCUS_DISTANCE);
+            self.uniforms.origin = pivot - self.uniforms.w * distance;
+            self.uniforms.focus_distance = distance;
+            return;
+        }
         let w_vec3 = self.uniforms.w;
         let displacement_vec = w_vec3 * displacement;
         let new_origin = self.uniforms.origin + displacement_vec;
         self.uniforms.origin = Vec4::new(new_origin.x(), new_origin.y(), new_origin.z());
     }
-    pub fn position(&self) -> Vec4 {
-        let (sin_phi , cos_phi ) = self.yaw.sin_cos();
-        let (sin_theta , cos_theta ) = self.pitch.sin_cos();
-        Vec4::new(sin_phi * sin_theta ,cos_theta ,cos_phi * sin_theta)
-    }
-    pub fn rotate(&mut self, dx:f32, dy :f32){
-            const MAX_PITCH : f32 = 179.0_f32.to_radians();
-            self.yaw += dx;
-            self.pitch += dy;
-            self.pitch = self.pitch.clamp(0.0001, MAX_PITCH);
-            let w : Vec4 = self.position().normalized();
-            let new_u : Vec4 = w.cross(&Vec4::new(0.0, 1.0,0.0)).normalized();
-            let new_v : Vec4 = new_u.cross(&w);
-            self.uniforms.u = new_u;
-            self.uniforms.v = new_v;
-            self.uniforms.w = w;
-    }
-
-    pub fn translate(&mut self,key : KeyEvent){
-        let move_speed = 0.1;
-      if key.physical_key == PhysicalKey::Code(winit::keyboard::KeyCode::KeyW) {
-            self.uniforms.origin = self.uniforms.origin + self.uniforms.w * move_speed;
+    pub fn aperture_radius(&self) -> f32 {
+        self.uniforms.aperture_radius
+    }
+
+    pub fn set_aperture_radius(&mut self, radius: f32) {
+        self.uniforms.aperture_radius = radius.max(0.);
+    }
+
+    pub fn focus_distance(&self) -> f32 {
+        self.uniforms.focus_distance
+    }
+
+    pub fn set_focus_distance(&mut self, distance: f32) {
+        self.uniforms.focus_distance = distance.max(MIN_FOCUS_DISTANCE);
+    }
+
+    pub fn aperture_blades(&self) -> u32 {
+        self.uniforms.aperture_blades
+    }
+
+    /// Makes the aperture, and with it the out of focus highlights, a regular polygon with
+    /// `blades` sides. Fewer than 3 blades make it round.
+    pub fn set_aperture_blades(&mut self, blades: u32) {
+        self.uniforms.aperture_blades = if blades < 3 { 0 } else { blades };
+    }
+
+    /// Returns the camera ray of `trace_path` in shaders.wgsl through the point (x, y) of an image
+    /// of the given size in pixels, where the centre of pixel (i, j) is at (i + 0.5, j + 0.5), and
+    /// through the centre of the lens, or None if the projection does not cover the point. For
+    /// projections onto a plane, the component of the direction along the view direction is 1, so
+    /// the distance to a hit along the ray is also its distance from the camera plane.
+    pub fn ray_through(&self, x: f32, y: f32, width: u32, height: u32) -> Option<Ray> {
+        let aspect_ratio = width as f32 / height as f32;
+        let uv_x = (2. * x / width as f32 - 1.) * aspect_ratio;
+        let uv_y = 1. - 2. * y / height as f32;
+        let CameraUniforms { origin, u, v, w, vfov, orthographic_distance, .. } = self.uniforms;
+        let half_vfov = 0.5 * vfov;
+        let tan_half_vfov = half_vfov.min(MAX_PLANAR_HALF_VFOV).tan();
+
+        // Same as `camera_ray` in shaders.wgsl.
+        let ray = match self.projection() {
+            Projection::Perspective => {
+                Ray::new(origin, u * (uv_x * tan_half_vfov) + v * (uv_y * tan_half_vfov) + w)
+            }
+            Projection::Orthographic => {
+                let scale = tan_half_vfov * orthographic_distance;
+                Ray::new(origin + u * (uv_x * scale) + v * (uv_y * scale), w)
+            }
+            Projection::Equirectangular => {
+                let longitude = PI * uv_x / aspect_ratio;
+                let latitude = 0.5 * PI * uv_y;
+                let horizontal = u * longitude.sin() + w * longitude.cos();
+                Ray::new(origin, horizontal * latitude.cos() + v * latitude.sin())
+            }
+            Projection::Fisheye => {
+                let r = uv_x.hypot(uv_y);
+                let theta = r * half_vfov;
+                if r == 0. {
+                    return Some(Ray::new(origin, w));
+                }
+                if theta > PI {
+                    return None;
+                }
+                let scale = theta.sin() / r;
+                Ray::new(origin, u * (uv_x * scale) + v * (uv_y * scale) + w * theta.cos())
+            }
+        };
+        Some(ray)
+    }
+
+    pub fn projection(&self) -> Projection {
+        Projection::from_u32(self.uniforms.projection)
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.uniforms.projection = projection as u32;
+    }
+
+    /// The vertical field of view in degrees.
+    pub fn vfov(&self) -> f32 {
+        self.uniforms.vfov.to_degrees()
+    }
+
+    pub fn set_vfov(&mut self, degrees: f32) {
+        self.uniforms.vfov = degrees.clamp(MIN_VFOV, MAX_VFOV).to_radians();
+    }
+
+    /// Handles the projection controls: `P` cycles through the projections, and `Z` and `X`
+    /// narrow and widen the field of view. Returns whether the key changed the projection.
+    pub fn adjust_projection(&mut self, key: &KeyEvent) -> bool {
+        match key.physical_key {
+            PhysicalKey::Code(KeyCode::KeyP) if !key.repeat => {
+                self.set_projection(self.projection().next())
+            }
+            PhysicalKey::Code(KeyCode::KeyZ) => self.set_vfov(self.vfov() / VFOV_STEP),
+            PhysicalKey::Code(KeyCode::KeyX) => self.set_vfov(self.vfov() * VFOV_STEP),
+            _ => return false,
         }
-        if key.physical_key == PhysicalKey::Code(winit::keyboard::KeyCode::KeyS) {
-            self.uniforms.origin = self.uniforms.origin - self.uniforms.w * move_speed;
+        true
+    }
+
+    #[cfg(test)]
+    pub fn origin(&self) -> Vec4 {
+        self.uniforms.origin
+    }
+
+    /// The right, up and view directions of the image.
+    #[cfg(test)]
+    pub fn axes(&self) -> [Vec4; 3] {
+        [self.uniforms.u, self.uniforms.v, self.uniforms.w]
+    }
+
+    pub fn orbit(&self) -> bool {
+        self.orbit
+    }
+
+    pub fn set_orbit(&mut self, orbit: bool) {
+        self.orbit = orbit;
+    }
+
+    // The point in focus, which the camera orbits around.
+    fn pivot(&self) -> Vec4 {
+        self.uniforms.origin + self.uniforms.w * self.uniforms.focus_distance
+    }
+
+    // The view direction for the yaw and pitch.
+    fn view_direction(&self) -> Vec4 {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        let [forward, right] = horizontal_axes(self.up);
+        (right * sin_yaw + forward * cos_yaw) * sin_pitch + self.up * cos_pitch
+    }
+
+    // Derives the axes in the uniforms from the yaw and pitch.
+    fn update_axes(&mut self) {
+        let w = self.view_direction();
+        let u = w.cross(&self.up).normalized();
+        self.uniforms.u = u;
+        self.uniforms.v = u.cross(&w);
+        self.uniforms.w = w;
+    }
+
+    /// Turns the camera by `dx` radians around the up axis and tilts it by `dy` radians, short of
+    /// looking straight up or down. In orbit mode the camera also moves around the point in focus
+    /// so that it keeps looking at it from the same distance.
+    pub fn rotate(&mut self, dx: f32, dy: f32) {
+        let pivot = self.pivot();
+        self.yaw = (self.yaw + dx) % TAU;
+        self.pitch = (self.pitch + dy).clamp(MIN_PITCH, MAX_PITCH);
+        self.update_axes();
+        if self.orbit {
+            self.uniforms.origin = pivot - self.uniforms.w * self.uniforms.focus_distance;
         }
-        if key.physical_key == PhysicalKey::Code(winit::keyboard::KeyCode::KeyA) {
-            self.uniforms.origin = self.uniforms.origin - self.uniforms.u * move_speed;
+    }
+
+    /// Moves the camera `distance` along the directions held in `movement`, or further while
+    /// sprinting: forward and backward along the view direction, sideways, and vertically along
+    /// the up direction that the camera turns around. Returns whether the camera moved.
+    pub fn fly(&mut self, movement: &Movement, distance: f32) -> bool {
+        let axis = |positive: bool, negative: bool| positive as i32 as f32 - negative as i32 as f32;
+        let direction = self.uniforms.w * axis(movement.forward, movement.backward)
+            + self.uniforms.u * axis(movement.right, movement.left)
+            + self.up * axis(movement.up, movement.down);
+        if direction.length_squared() == 0. || distance <= 0. {
+            return false;
         }
-        if key.physical_key == PhysicalKey::Code(winit::keyboard::KeyCode::KeyD) {
-            self.uniforms.origin = self.uniforms.origin + self.uniforms.u * move_speed;
+        let distance = if movement.sprint { distance * SPRINT_FACTOR } else { distance };
+        self.uniforms.origin += direction.normalized() * distance;
+        true
+    }
+
+    /// Handles the lens controls: `[` and `]` close and open the aperture, `,` and `.` move the
+    /// focus nearer and farther, and `B` cycles through the aperture shapes. Returns whether the
+    /// key changed the lens.
+    pub fn adjust_lens(&mut self, key: &KeyEvent) -> bool {
+        let PhysicalKey::Code(code) = key.physical_key else {
+            return false;
+        };
+        let radius = self.aperture_radius();
+        match code {
+            KeyCode::BracketLeft if radius > 0. => {
+                let radius = radius / APERTURE_STEP;
+                self.set_aperture_radius(if radius < MIN_APERTURE_RADIUS { 0. } else { radius });
+            }
+            KeyCode::BracketRight => {
+                self.set_aperture_radius((radius * APERTURE_STEP).max(MIN_APERTURE_RADIUS));
+            }
+            KeyCode::Comma => self.set_focus_distance(self.focus_distance() / FOCUS_STEP),
+            KeyCode::Period => self.set_focus_distance(self.focus_distance() * FOCUS_STEP),
+            KeyCode::KeyB if !key.repeat => {
+                let blades = match self.aperture_blades() {
+                    0 => 3,
+                    MAX_APERTURE_BLADES.. => 0,
+                    blades => blades + 1,
+                };
+                self.set_aperture_blades(blades);
+            }
+            _ => return false,
         }
+        true
     }
-    pub fn look_at(origin: Vec4, center: Vec4, up: Vec4) -> Camera {
-        let w = (center - origin).normalized();
-        let u = w.cross(&up).normalized();
-        let v = u.cross(&w);
 
-        Camera {
+    /// A pinhole camera at `origin` looking at `center`, which is where the focus is set once the
+    /// aperture is opened, and turning around `up`. A camera looking along `up` is tilted by
+    /// `MIN_PITCH` from it.
+    pub fn look_at(origin: Vec4, center: Vec4, up: Vec4) -> Camera {
+        let up = up.normalized();
+        let direction = (center - origin).normalized();
+        let [forward, right] = horizontal_axes(up);
+        let mut camera = Camera {
             uniforms: CameraUniforms {
-                origin,u,v,w,      
+                origin,
+                u: Vec4::zero(),
+                v: Vec4::zero(),
+                w: Vec4::zero(),
+                aperture_radius: 0.,
+                focus_distance: (center - origin).length(),
+                aperture_blades: 0,
+                projection: Projection::Perspective as u32,
+                vfov: DEFAULT_VFOV.to_radians(),
+                orthographic_distance: (center - origin).length(),
+                _padding: [0; 2],
             },
-            pitch : 0.0,
-            yaw : 0.0,
+            yaw: direction.dot(&right).atan2(direction.dot(&forward)),
+            pitch: direction.dot(&up).clamp(-1., 1.).acos().clamp(MIN_PITCH, MAX_PITCH),
+            up,
+            orbit: false,
+        };
+        camera.update_axes();
+        camera
+    }
+}
+
+// Unit vectors perpendicular to `up` that the yaw is measured from: the view direction has a yaw
+// of 0 towards the first and of 90 degrees towards the second. For the default up of +y, they are
+// +z and +x.
+fn horizontal_axes(up: Vec4) -> [Vec4; 2] {
+    let reference = if up.z().abs() < 0.9 { Vec4::new(0., 0., 1.) } else { Vec4::new(1., 0., 0.) };
+    let forward = (reference - up * up.dot(&reference)).normalized();
+    [forward, up.cross(&forward)]
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            sampling::{self, Rng},
+            test_util::random_point,
+        },
+    };
+
+    // The angles that `Camera::look_at` derives must give back the view direction, with the right
+    // axis level with the up direction, and turning the camera by nothing must not change its axes.
+    // Turning in orbit mode must keep the camera looking at the same point from the same distance.
+    #[test]
+    fn look_at_round_trips_through_angles() {
+        const CAMERAS: u32 = 10_000;
+
+        let mut rng = Rng::new(6);
+        let close = |a: Vec4, b: Vec4| (a - b).length() <= 1e-3 * b.length().max(1.);
+        let close_axes = |a: [Vec4; 3], b: [Vec4; 3]| (0..3).all(|i| close(a[i], b[i]));
+        for _ in 0..CAMERAS {
+            let origin = random_point(&mut rng, 10.);
+            let up = sampling::sample_sphere(&mut rng);
+            let direction = sampling::sample_sphere(&mut rng);
+            // Directions closer to the up axis than the camera can look are tilted away from it.
+            if direction.dot(&up).abs() > 0.999 {
+                continue;
+            }
+            let center = origin + direction * rng.range(0.1, 10.);
+            let mut camera = Camera::look_at(origin, center, up * rng.range(0.1, 10.));
+            let u = direction.cross(&up).normalized();
+            let axes = camera.axes();
+            assert!(
+                close_axes(axes, [u, u.cross(&direction), direction]),
+                "looking at {:?} from {:?} gave the axes {:?}",
+                center,
+                origin,
+                axes
+            );
+            camera.rotate(0., 0.);
+            assert!(
+                close_axes(camera.axes(), axes),
+                "turning by nothing changed the axes {:?} to {:?}",
+                axes,
+                camera.axes()
+            );
+
+            camera.set_orbit(true);
+            camera.rotate(rng.range(-PI, PI), rng.range(-1., 1.));
+            let [_, _, w] = camera.axes();
+            assert!(
+                close(camera.origin(), center - w * (center - origin).length()),
+                "orbiting {:?} moved the camera to {:?}",
+                center,
+                camera.origin()
+            );
+            // The orientation after turning is the one that looking at the pivot gives.
+            let looking = Camera::look_at(camera.origin(), center, up);
+            assert!(
+                close_axes(looking.axes(), camera.axes()),
+                "orbited to {:?} but looking at {:?} gives {:?}",
+                camera.axes(),
+                center,
+                looking.axes()
+            );
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,417 @@
+//! Environment maps that light the scene from infinitely far away, loaded from equirectangular
+//! Radiance .hdr or OpenEXR images.
+
+use {
+    anyhow::{bail, Context, Result},
+    std::{
+        f32::consts::PI,
+        fs::File,
+        io::{BufRead, BufReader, Read},
+        path::Path,
+    },
+};
+
+/// An equirectangular image of the radiance arriving from every direction. Rows go from +y at the
+/// top to -y at the bottom, and the centre column is towards -z with +x to its right.
+#[derive(Clone)]
+pub struct EnvironmentMap {
+    pub width: u32,
+    pub height: u32,
+    // RGBA in row major order, the alpha is unused.
+    pub texels: Vec<[f32; 4]>,
+}
+
+impl EnvironmentMap {
+    /// Loads a .hdr or .exr image, depending on the extension of `path`.
+    pub fn load(path: &Path) -> Result<EnvironmentMap> {
+        let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        let map = match extension.as_deref() {
+            Some("hdr") => File::open(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| read_hdr(BufReader::new(file))),
+            Some("exr") => read_exr(path),
+            _ => bail!(
+                "{}: unsupported environment map format, expected .hdr or .exr",
+                path.display()
+            ),
+        };
+        let map =
+            map.with_context(|| format!("failed to load environment map {}", path.display()))?;
+        debug_assert!(map.width > 0 && map.height > 0);
+        if map.texels.iter().any(|t| t[..3].iter().any(|c| !c.is_finite() || *c < 0.)) {
+            bail!("environment map {} has negative or non-finite texels", path.display());
+        }
+        Ok(map)
+    }
+
+    /// Halves the resolution by averaging blocks of 2x2 texels, rounding odd sizes up.
+    pub fn downsampled(&self) -> EnvironmentMap {
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let mut texels = Vec::with_capacity(width as usize * height as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0.; 4];
+                let mut count = 0.;
+                for (sx, sy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let (sx, sy) = (2 * x + sx, 2 * y + sy);
+                    if sx < self.width && sy < self.height {
+                        let texel = self.texels[(sy * self.width + sx) as usize];
+                        (0..4).for_each(|i| sum[i] += texel[i]);
+                        count += 1.;
+                    }
+                }
+                texels.push(sum.map(|c| c / count));
+            }
+        }
+        EnvironmentMap { width, height, texels }
+    }
+}
+
+/// A piecewise constant distribution of directions over the texels of an environment map, for
+/// sampling it as a light. Texels are picked with probability proportional to their luminance times
+/// the solid angle that they cover, by first picking a row and then a column within it, and the
+/// direction is uniformly distributed over the texel in longitude and latitude.
+/// See Pharr et al., "Physically Based Rendering", 4th edition, section A.5.
+pub struct EnvironmentDistribution {
+    width: u32,
+    // For each row, the probability of picking one of its first x + 1 columns once it is picked.
+    conditional_cdf: Vec<f32>,
+    // The probability of picking one of the first y + 1 rows.
+    marginal_cdf: Vec<f32>,
+}
+
+impl EnvironmentDistribution {
+    pub fn new(map: &EnvironmentMap) -> EnvironmentDistribution {
+        let (width, height) = (map.width as usize, map.height as usize);
+        let mut conditional_cdf = Vec::with_capacity(width * height);
+        let mut row_weights = Vec::with_capacity(height);
+        for (y, row) in map.texels.chunks_exact(width).enumerate() {
+            // The solid angle of a texel is proportional to the sine of its polar angle.
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let weights =
+                row.iter().map(|&[r, g, b, _]| sin_theta * (0.2126 * r + 0.7152 * g + 0.0722 * b));
+            row_weights.push(append_cdf(&mut conditional_cdf, weights));
+        }
+        let mut marginal_cdf = Vec::with_capacity(height);
+        append_cdf(&mut marginal_cdf, row_weights.into_iter());
+        EnvironmentDistribution { width: map.width, conditional_cdf, marginal_cdf }
+    }
+
+    /// The CDFs in the layout of the `environment_cdf` texture in shaders.wgsl: a row of
+    /// `width + 1` texels for each row of the map, holding its conditional CDF followed by the
+    /// marginal CDF of the row.
+    pub fn texels(&self) -> Vec<f32> {
+        self.conditional_cdf
+            .chunks_exact(self.width as usize)
+            .zip(&self.marginal_cdf)
+            .flat_map(|(row, &marginal)| row.iter().copied().chain([marginal]))
+            .collect()
+    }
+}
+
+// Appends the normalized CDF of `weights` to `cdf`, or that of equal weights if they are all zero,
+// and returns the sum of the weights.
+fn append_cdf(cdf: &mut Vec<f32>, weights: impl Iterator<Item = f32> + Clone) -> f32 {
+    let total: f32 = weights.clone().sum();
+    let count = weights.clone().count();
+    let mut sum = 0.;
+    for (i, weight) in weights.enumerate() {
+        sum += weight;
+        cdf.push(if total > 0. { sum / total } else { (i + 1) as f32 / count as f32 });
+    }
+    // Rounding can leave the last value short of 1, which would let a random number pass it.
+    if let Some(last) = cdf.last_mut() {
+        *last = 1.;
+    }
+    total
+}
+
+/// Decodes the first RGBA layer of an OpenEXR image, with an alpha of 1.
+pub fn read_exr(path: &Path) -> Result<EnvironmentMap> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| (resolution.width(), vec![[0.; 4]; resolution.area()]),
+        |(width, texels): &mut (usize, Vec<[f32; 4]>),
+         position,
+         (r, g, b, _): (f32, f32, f32, f32)| {
+            texels[position.y() * *width + position.x()] = [r, g, b, 1.];
+        },
+    )?;
+    let (width, texels) = image.layer_data.channel_data.pixels;
+    let height = texels.len() / width.max(1);
+    texel_count(width as u32, height as u32)?;
+    Ok(EnvironmentMap { width: width as u32, height: height as u32, texels })
+}
+
+// Environment maps larger than this are rejected before anything is allocated for them, so that a
+// corrupt header cannot exhaust the memory. It is twice the texels of a 16k map.
+const MAX_TEXELS: usize = 1 << 28;
+
+// The number of texels of a `width` by `height` map, or an error if it is empty or too large.
+fn texel_count(width: u32, height: u32) -> Result<usize> {
+    if width == 0 || height == 0 {
+        bail!("the image is empty");
+    }
+    match (width as usize).checked_mul(height as usize) {
+        Some(count) if count <= MAX_TEXELS => Ok(count),
+        _ => bail!("the image is too large, {}x{} texels", width, height),
+    }
+}
+
+/// Decodes a Radiance RGBE image in the standard orientation (`-Y height +X width`), with flat or
+/// run length encoded scanlines. The old style of run length encoding, which predates 1991, is not
+/// supported.
+/// See Greg Ward, "Real Pixels", Graphics Gems II, and the format description at
+/// https://paulbourke.net/dataformats/pic/
+pub fn read_hdr(mut reader: impl BufRead) -> Result<EnvironmentMap> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        bail!("not a Radiance image");
+    }
+    // The header is a list of variables ending with an empty line.
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("the header does not end");
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                bail!("unsupported pixel format {}, expected 32-bit_rle_rgbe", format);
+            }
+        }
+    }
+    line.clear();
+    reader.read_line(&mut line)?;
+    let (height, width) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", height, "+X", width] => (height.parse::<u32>()?, width.parse::<u32>()?),
+        _ => bail!("unsupported image orientation {}", line.trim_end()),
+    };
+
+    let mut texels = Vec::with_capacity(texel_count(width, height)?);
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    for _ in 0..height {
+        read_hdr_scanline(&mut reader, &mut scanline)?;
+        texels.extend(scanline.iter().map(|&rgbe| rgbe_to_float(rgbe)));
+    }
+    Ok(EnvironmentMap { width, height, texels })
+}
+
+fn read_hdr_scanline(reader: &mut impl Read, scanline: &mut [[u8; 4]]) -> Result<()> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    reader.read_exact(&mut first)?;
+    // Run length encoded scanlines start with 2, 2 and their width, which is never a valid pixel
+    // since its mantissa is not normalized. They store the four components one after the other.
+    if !(8..0x8000).contains(&width) || first[..2] != [2, 2] || first[2] & 0x80 != 0 {
+        scanline[0] = first;
+        for pixel in &mut scanline[1..] {
+            reader.read_exact(pixel)?;
+        }
+        return Ok(());
+    }
+    if usize::from(first[2]) << 8 | usize::from(first[3]) != width {
+        bail!("a scanline has the wrong width");
+    }
+    for component in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 1];
+            reader.read_exact(&mut count)?;
+            // Counts above 128 are runs of a single value, the others are followed by as many
+            // values.
+            let (run, count) = match count[0] {
+                count @ 129.. => (true, usize::from(count - 128)),
+                count => (false, usize::from(count)),
+            };
+            if count == 0 || x + count > width {
+                bail!("a scanline has a bad run length");
+            }
+            let mut value = [0u8; 1];
+            if run {
+                reader.read_exact(&mut value)?;
+            }
+            for pixel in &mut scanline[x..x + count] {
+                if !run {
+                    reader.read_exact(&mut value)?;
+                }
+                pixel[component] = value[0];
+            }
+            x += count;
+        }
+    }
+    Ok(())
+}
+
+// Each component is the mantissa of a float whose exponent, biased by 128, is shared by all three.
+fn rgbe_to_float([r, g, b, e]: [u8; 4]) -> [f32; 4] {
+    if e == 0 {
+        return [0., 0., 0., 1.];
+    }
+    let scale = (f32::from(e) - 136.).exp2();
+    let [r, g, b] = [r, g, b].map(|c| (f32::from(c) + 0.5) * scale);
+    [r, g, b, 1.]
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            math::Vec4,
+            sampling::{self, Rng},
+            test_util::{assert_estimate, estimate},
+        },
+    };
+
+    impl EnvironmentDistribution {
+        // The number of rows of the map.
+        fn height(&self) -> u32 {
+            self.marginal_cdf.len() as u32
+        }
+
+        /// The probability of picking the texel (x, y).
+        fn texel_probability(&self, x: u32, y: u32) -> f32 {
+            let row = &self.conditional_cdf[(y * self.width) as usize..][..self.width as usize];
+            cdf_step(&self.marginal_cdf, y as usize) * cdf_step(row, x as usize)
+        }
+
+        /// Same as `sample_environment` in shaders.wgsl without the rotation. Returns a direction
+        /// of the map for the random numbers `u` and its density with respect to solid angle.
+        fn sample(&self, u: [f32; 2]) -> (Vec4, f32) {
+            let (y, v) = sample_cdf(&self.marginal_cdf, u[1]);
+            let row = &self.conditional_cdf[y * self.width as usize..][..self.width as usize];
+            let (x, u) = sample_cdf(row, u[0]);
+            let texel_u = (x as f32 + u) / self.width as f32;
+            let texel_v = (y as f32 + v) / self.height() as f32;
+            let direction = texel_direction(texel_u, texel_v);
+            (direction, self.pdf(direction))
+        }
+
+        /// Same as `environment_pdf` in shaders.wgsl without the rotation: the density of sampling
+        /// `direction`, a unit vector, with respect to solid angle.
+        fn pdf(&self, direction: Vec4) -> f32 {
+            let (x, y) = self.texel(direction);
+            let sin_theta = (1. - direction.y() * direction.y()).max(0.).sqrt();
+            if sin_theta == 0. {
+                return 0.;
+            }
+            // The density over the map scaled to [0, 1]^2, and the Jacobian of the mapping to
+            // directions, d(omega) = 2 pi^2 sin(theta) du dv.
+            let density = self.texel_probability(x, y) * (self.width * self.height()) as f32;
+            density / (2. * PI * PI * sin_theta)
+        }
+
+        /// Same as `environment_texel` in shaders.wgsl without the rotation: the texel of the map
+        /// towards `direction`, a unit vector.
+        fn texel(&self, direction: Vec4) -> (u32, u32) {
+            let longitude = direction.x().atan2(-direction.z());
+            let u = (0.5 + longitude / (2. * PI)).rem_euclid(1.);
+            let v = direction.y().clamp(-1., 1.).acos() / PI;
+            let x = ((u * self.width as f32) as u32).min(self.width - 1);
+            let y = ((v * self.height() as f32) as u32).min(self.height() - 1);
+            (x, y)
+        }
+    }
+
+    // The probability of the i-th outcome of a CDF.
+    fn cdf_step(cdf: &[f32], i: usize) -> f32 {
+        cdf[i] - if i > 0 { cdf[i - 1] } else { 0. }
+    }
+
+    // Same as `sample_cdf` in shaders.wgsl: picks the first outcome whose CDF value is above `u`
+    // and returns it with the position of `u` within its step, remapped to [0, 1).
+    fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+        let i = cdf.partition_point(|&c| c <= u).min(cdf.len() - 1);
+        let lo = if i > 0 { cdf[i - 1] } else { 0. };
+        let step = cdf[i] - lo;
+        (i, if step > 0. { ((u - lo) / step).min(1. - f32::EPSILON) } else { 0.5 })
+    }
+
+    // The direction towards a point (u, v) of an equirectangular map, see `EnvironmentMap`.
+    fn texel_direction(u: f32, v: f32) -> Vec4 {
+        let longitude = 2. * PI * (u - 0.5);
+        let (sin_theta, cos_theta) = (PI * v).sin_cos();
+        Vec4::new(sin_theta * longitude.sin(), cos_theta, -sin_theta * longitude.cos())
+    }
+
+    // The density of environment map sampling must integrate to one over the sphere, and the texels
+    // that sampled directions fall into must follow the probabilities that the density is made of.
+    // The map has a small bright sun, like the outdoor maps that need importance sampling, and a
+    // black row that must never be sampled.
+    #[test]
+    fn sampling_matches_pdf() {
+        const WIDTH: u32 = 64;
+        const HEIGHT: u32 = 32;
+        const SAMPLES: u32 = 1_000_000;
+
+        let texels = (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let (x, y) = (i % WIDTH, i / WIDTH);
+                let radiance = match (x, y) {
+                    (40..=41, 8..=9) => 1000.,
+                    (_, 20) => 0.,
+                    _ => 0.2 + x as f32 / WIDTH as f32,
+                };
+                [radiance, 0.5 * radiance, 0.25 * radiance, 1.]
+            })
+            .collect();
+        let map = EnvironmentMap { width: WIDTH, height: HEIGHT, texels };
+        let distribution = EnvironmentDistribution::new(&map);
+
+        let mut rng = Rng::new(7);
+        assert_estimate(
+            "environment pdf integral",
+            estimate(SAMPLES, || distribution.pdf(sampling::sample_sphere(&mut rng)) * 4. * PI),
+            1.,
+        );
+
+        let mut counts = vec![0u32; (WIDTH * HEIGHT) as usize];
+        for _ in 0..SAMPLES {
+            let (direction, pdf) = distribution.sample([rng.next_f32(), rng.next_f32()]);
+            assert!(pdf.is_finite() && pdf >= 0., "sampled {:?} with pdf {}", direction, pdf);
+            let (x, y) = distribution.texel(direction);
+            counts[(y * WIDTH + x) as usize] += 1;
+        }
+        // Each count is binomial, so allow 5 standard deviations.
+        let (mut chi_squared, mut degrees_of_freedom) = (0f32, -1f32);
+        for (i, &count) in counts.iter().enumerate() {
+            let (x, y) = (i as u32 % WIDTH, i as u32 / WIDTH);
+            let expected = SAMPLES as f32 * distribution.texel_probability(x, y);
+            assert!(
+                (count as f32 - expected).abs() <= 5. * expected.sqrt() + 1.,
+                "texel ({}, {}) was sampled {} times, expected {}",
+                x,
+                y,
+                count,
+                expected
+            );
+            if expected > 0. {
+                chi_squared += (count as f32 - expected).powi(2) / expected;
+                degrees_of_freedom += 1.;
+            }
+        }
+        // Taken together, the counts must not be further from their expected values than 5
+        // standard deviations of the chi-squared distribution either.
+        let threshold = degrees_of_freedom + 5. * (2. * degrees_of_freedom).sqrt();
+        assert!(
+            chi_squared <= threshold,
+            "chi squared of the texel counts is {}, expected at most {}",
+            chi_squared,
+            threshold
+        );
+    }
+
+    // Empty and huge images must be rejected from their header, before any texel is read.
+    #[test]
+    fn read_hdr_rejects_bad_sizes() {
+        for size in ["-Y 4 +X 0", "-Y 0 +X 4", "-Y 100000 +X 100000"] {
+            let file = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", size);
+            assert!(read_hdr(file.as_bytes()).is_err(), "read an image of size {}", size);
+        }
+    }
+}
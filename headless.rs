@@ -0,0 +1,138 @@
+//! Offline rendering to an image file, for machines without a display or a GPU.
+
+use {
+    crate::{
+        output::{Format, Image},
+        render::{PathTracer, SampleBudget},
+        sampler::Sampler,
+        scene::Scene,
+        tonemap::ToneMapping,
+    },
+    anyhow::{bail, Context, Result},
+    std::{path::PathBuf, time::Instant},
+};
+
+// Samples per pixel traced by each submission. Larger batches have less overhead, but a submission
+// that runs for too long can make the driver reset the GPU.
+const SAMPLES_PER_BATCH: u32 = 8;
+
+/// What to render and where to write it, see `--output` in main.rs.
+pub struct Options {
+    pub output: PathBuf,
+    pub samples_per_pixel: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Display transform of 8-bit output. Float output holds the radiance unchanged.
+    pub tone_mapping: ToneMapping,
+    pub exposure: f32,
+    pub max_bounces: u32,
+    /// See `PathTracer::set_russian_roulette`.
+    pub russian_roulette: Option<u32>,
+    pub sampler: Sampler,
+    /// A converged render of the same view, for example with many more samples, that the result
+    /// is compared with.
+    pub reference: Option<PathBuf>,
+}
+
+/// Renders `scene` from its camera with `options.samples_per_pixel` samples and writes the result
+/// to `options.output`.
+pub async fn render(scene: &Scene, options: &Options) -> Result<()> {
+    // Check the file name and the reference before rendering rather than after.
+    Format::from_path(&options.output)?;
+    let reference = options.reference.as_deref().map(Image::read).transpose()?;
+    if let Some(reference) = &reference {
+        if reference.size() != (options.width, options.height) {
+            bail!(
+                "the reference is {:?} pixels but the image would be {:?}",
+                reference.size(),
+                (options.width, options.height)
+            );
+        }
+    }
+    let (device, queue) = connect_to_gpu().await?;
+
+    // Only the size of the target matters since nothing is displayed, but the path tracer also
+    // creates its display pipeline for the format.
+    let target_config = wgpu::SurfaceConfiguration {
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        width: options.width,
+        height: options.height,
+        present_mode: wgpu::PresentMode::Fifo,
+        alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+        view_formats: vec![],
+        desired_maximum_frame_latency: 1,
+    };
+    let mut renderer = PathTracer::new(device.clone(), queue, scene, &target_config);
+    renderer.set_max_bounces(options.max_bounces);
+    renderer.set_russian_roulette(options.russian_roulette);
+    renderer.set_sampler(options.sampler);
+
+    let start = Instant::now();
+    let mut remaining = options.samples_per_pixel;
+    while remaining > 0 {
+        let samples = remaining.min(SAMPLES_PER_BATCH);
+        renderer.set_sample_budget(SampleBudget::Fixed(samples));
+        renderer.trace(&scene.camera);
+        remaining -= samples;
+        // Wait for each batch so that a slow device does not build up a long queue of work.
+        device
+            .poll(wgpu::PollType::Wait)
+            .context("failed to wait for the GPU")?;
+    }
+    let image = Image::capture(&renderer)?;
+    let statistics = renderer.read_path_statistics()?;
+    println!(
+        "rendered {} samples per pixel in {:.1?}, {:.2} bounces per path ({:.1}% terminated by \
+         russian roulette)",
+        renderer.sample_count(),
+        start.elapsed(),
+        statistics.mean_bounces(),
+        100. * statistics.roulette_fraction()
+    );
+    if let (Some(reference), Some(path)) = (&reference, &options.reference) {
+        println!(
+            "RMSE {:.6} against {} with the {} sampler",
+            image.rmse(reference)?,
+            path.display(),
+            options.sampler
+        );
+    }
+
+    image.write(&options.output, options.tone_mapping, options.exposure)?;
+    println!("wrote {}", options.output.display());
+    Ok(())
+}
+
+async fn connect_to_gpu() -> Result<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::default();
+
+    // Prefer a hardware GPU, but fall back to a software implementation (such as llvmpipe or
+    // WARP) on machines that do not have one.
+    let mut options = wgpu::RequestAdapterOptions {
+        power_preference: wgpu::PowerPreference::HighPerformance,
+        force_fallback_adapter: false,
+        compatible_surface: None,
+    };
+    let adapter = match instance.request_adapter(&options).await {
+        Ok(adapter) => adapter,
+        Err(_) => {
+            options.force_fallback_adapter = true;
+            instance
+                .request_adapter(&options)
+                .await
+                .context("failed to find an adapter, including a fallback one")?
+        }
+    };
+
+    // Software adapters often report lower limits than the defaults, so ask for what the adapter
+    // supports and let pipeline creation report anything that is missing.
+    let (device, queue) = adapter
+        .request_device(&wgpu::DeviceDescriptor {
+            required_limits: adapter.limits(),
+            ..Default::default()
+        })
+        .await
+        .context("failed to connect to the GPU")?;
+    Ok((device, queue))
+}
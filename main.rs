@@ -1,57 +1,320 @@
 use {
-    anyhow::{Context, Result},
+    anyhow::{anyhow, bail, Context, Result},
     winit::{
-        event::{Event, WindowEvent,MouseScrollDelta,DeviceEvent},
+        dpi::PhysicalPosition,
+        event::{Event, WindowEvent,MouseScrollDelta,DeviceEvent,ElementState,MouseButton},
         event_loop::{ControlFlow, EventLoop},
+        keyboard::{KeyCode, PhysicalKey},
         window::{Window, WindowBuilder},
     },
 };
 use winit::dpi::LogicalPosition;
-use std::time::Instant;
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
 
 mod render;
 mod math;
 mod camera;
+mod bsdf;
+mod bvh;
+mod environment;
+mod headless;
+mod mesh;
+mod output;
+mod sampler;
+mod sampling;
+mod scene;
+mod sky;
+#[cfg(test)]
+mod test_util;
+mod tonemap;
+mod validate;
 
-use crate::camera::Camera;
-use crate::math::Vec4;
+use crate::{sampler::Sampler, scene::Scene, tonemap::ToneMapping};
 
 const WIDTH: u32 = 800;
 const HEIGHT: u32 = 600;
+const DEFAULT_SAMPLES_PER_PIXEL: u32 = 256;
+// Bounces after which `--roulette` can terminate paths, unless `--roulette-depth` is given.
+const DEFAULT_ROULETTE_DEPTH: u32 = 3;
+// Target frame time of `--spf auto`. Above the refresh interval of common displays, since frames
+// cannot be shorter than that with vsync.
+const AUTO_FRAME_TIME: Duration = Duration::from_millis(50);
+// Change of exposure per key press, in stops.
+const EXPOSURE_STEP: f32 = 0.5;
+// Change of the environment rotation per key press, in degrees, and of its intensity, in stops.
+const ENVIRONMENT_ROTATION_STEP: f32 = 15.;
+const ENVIRONMENT_INTENSITY_STEP: f32 = 0.5;
+// Change of the time of day of the physical sky per key press, in hours, and how fast it passes
+// while animated, in hours per second.
+const SKY_TIME_STEP: f32 = 0.25;
+const SKY_ANIMATION_SPEED: f32 = 1.;
+// Speed of the camera in scene units per second.
+const DEFAULT_SPEED: f32 = 1.;
+// Longest time that the camera moves for in one frame, so that it does not jump after a stall.
+const MAX_MOVEMENT_TIME: Duration = Duration::from_millis(100);
+
+const USAGE: &str = "\
+usage: path-tracer [SCENE] [OPTIONS]
+
+Renders SCENE, a RON scene file, or a built-in demo scene in a window.
+
+options:
+  --output FILE   render without a window and write FILE, an 8-bit .png or a 32-bit float .exr
+  --spp N         samples per pixel to render with --output (default 256)
+  --spf N|auto    samples per pixel per frame in the window, or auto to trace as many as fit in
+                  50 ms while the camera is still (default auto)
+  --width N       width of the window or image (default 800)
+  --height N      height of the window or image (default 600)
+  --tonemap NAME  tone mapping of the window and of .png output: linear, reinhard,
+                  extended-reinhard, aces or agx (default linear)
+  --exposure EV   exposure in stops (default 0)
+  --speed S       speed of the camera in scene units per second, 4 times faster with Shift
+                  (default 1)
+  --max-bounces N most bounces of a path, at most 64 (default 13)
+  --roulette      let Russian roulette terminate paths early, otherwise every path is traced until
+                  it escapes, hits a light or reaches --max-bounces
+  --roulette-depth N
+                  bounces after which Russian roulette can terminate paths, implies --roulette
+                  (default 3)
+  --sampler NAME  how the numbers of the samples are generated: random, sobol or blue-noise
+                  (default random)
+  --reference FILE
+                  print the RMSE of the image rendered with --output against FILE, a .exr of the
+                  same size
+  --hdr           display with a 16-bit float or 10-bit surface format if available
+  --validate      run the checks in validate.rs and exit";
+
+struct Args {
+    scene: Option<String>,
+    validate: bool,
+    output: Option<PathBuf>,
+    samples_per_pixel: u32,
+    // Samples per frame, or None to adapt them to the frame time.
+    samples_per_frame: Option<u32>,
+    width: u32,
+    height: u32,
+    tone_mapping: ToneMapping,
+    exposure: f32,
+    speed: f32,
+    hdr: bool,
+    max_bounces: u32,
+    roulette_depth: u32,
+    roulette: bool,
+    sampler: Sampler,
+    reference: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Args> {
+        let mut parsed = Args {
+            scene: None,
+            validate: false,
+            output: None,
+            samples_per_pixel: DEFAULT_SAMPLES_PER_PIXEL,
+            samples_per_frame: None,
+            width: WIDTH,
+            height: HEIGHT,
+            tone_mapping: ToneMapping::Linear,
+            exposure: 0.,
+            speed: DEFAULT_SPEED,
+            hdr: false,
+            max_bounces: render::DEFAULT_MAX_BOUNCES,
+            roulette_depth: DEFAULT_ROULETTE_DEPTH,
+            roulette: false,
+            sampler: Sampler::Random,
+            reference: None,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value =
+                |name: &str| args.next().ok_or_else(|| anyhow!("{} expects a value", name));
+            match arg.as_str() {
+                "--validate" => parsed.validate = true,
+                "--hdr" => parsed.hdr = true,
+                "--roulette" => parsed.roulette = true,
+                "--output" => parsed.output = Some(value(&arg)?.into()),
+                "--reference" => parsed.reference = Some(value(&arg)?.into()),
+                "--sampler" => parsed.sampler = value(&arg)?.parse()?,
+                "--spp" => parsed.samples_per_pixel = parse_positive(&arg, &value(&arg)?)?,
+                "--spf" => {
+                    parsed.samples_per_frame = match value(&arg)?.as_str() {
+                        "auto" => None,
+                        n => Some(parse_positive(&arg, n)?),
+                    }
+                }
+                "--width" => parsed.width = parse_positive(&arg, &value(&arg)?)?,
+                "--height" => parsed.height = parse_positive(&arg, &value(&arg)?)?,
+                "--tonemap" => parsed.tone_mapping = value(&arg)?.parse()?,
+                "--max-bounces" => {
+                    parsed.max_bounces = parse_positive(&arg, &value(&arg)?)?;
+                    if parsed.max_bounces > render::MAX_BOUNCE_LIMIT {
+                        bail!("{} must be at most {}", arg, render::MAX_BOUNCE_LIMIT);
+                    }
+                }
+                "--roulette-depth" => {
+                    parsed.roulette_depth = parse_positive(&arg, &value(&arg)?)?;
+                    parsed.roulette = true;
+                }
+                "--exposure" => {
+                    let ev = value(&arg)?;
+                    parsed.exposure = match ev.parse::<f32>() {
+                        Ok(ev) if ev.is_finite() => ev,
+                        _ => bail!("{} expects a number, got {}", arg, ev),
+                    }
+                }
+                "--speed" => {
+                    let speed = value(&arg)?;
+                    parsed.speed = match speed.parse::<f32>() {
+                        Ok(speed) if speed > 0. && speed.is_finite() => speed,
+                        _ => bail!("{} expects a positive number, got {}", arg, speed),
+                    }
+                }
+                "--help" | "-h" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                _ if arg.starts_with('-') => bail!("unknown option {}\n\n{}", arg, USAGE),
+                _ if parsed.scene.is_none() => parsed.scene = Some(arg),
+                _ => bail!("unexpected argument {}\n\n{}", arg, USAGE),
+            }
+        }
+        Ok(parsed)
+    }
+
+    // The depth that Russian roulette starts at, see `PathTracer::set_russian_roulette`.
+    fn russian_roulette(&self) -> Option<u32> {
+        self.roulette.then_some(self.roulette_depth)
+    }
+}
+
+fn parse_positive(name: &str, value: &str) -> Result<u32> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("{} expects a positive integer, got {}", name, value),
+    }
+}
 
 #[pollster::main]
 async fn main() -> Result<()> {
+    let mut args = Args::parse(std::env::args().skip(1))?;
+    if args.validate {
+        return validate::run();
+    }
+
+    let scene = match &args.scene {
+        Some(path) => Scene::load(path)?,
+        None => Scene::default_scene()?,
+    };
+
+    if let Some(output) = args.output.take() {
+        let options = headless::Options {
+            output,
+            samples_per_pixel: args.samples_per_pixel,
+            width: args.width,
+            height: args.height,
+            tone_mapping: args.tone_mapping,
+            exposure: args.exposure,
+            max_bounces: args.max_bounces,
+            russian_roulette: args.russian_roulette(),
+            sampler: args.sampler,
+            reference: args.reference,
+        };
+        return headless::render(&scene, &options).await;
+    }
+
     let event_loop = EventLoop::new()?;
-    let window_size = winit::dpi::PhysicalSize::new(WIDTH, HEIGHT);
+    let window_size = winit::dpi::PhysicalSize::new(args.width, args.height);
     let window = WindowBuilder::new()
         .with_inner_size(window_size)
-        .with_resizable(false)
         .with_title("GPU Path Tracer".to_string())
         .build(&event_loop)?;
     window.set_cursor_grab(winit::window::CursorGrabMode::Locked);
-    let (device, queue, surface) = connect_to_gpu(&window).await?;
+    let (device, queue, surface, mut surface_config) = connect_to_gpu(&window, args.hdr).await?;
 
     // TODO: initialize renderer
     let (device, queue, surface) = (device, queue, surface);
-    let mut renderer = render::PathTracer::new(device, queue);
-    let mut camera = Camera::look_at(
-            Vec4::new(0., 0.75, 1.),
-            Vec4::new(0., -0.5, -1.),
-            Vec4::new(0., 1., 0.),
-    ); 
+    let mut renderer = render::PathTracer::new(device.clone(), queue, &scene, &surface_config);
+    renderer.set_sample_budget(match args.samples_per_frame {
+        Some(samples) => render::SampleBudget::Fixed(samples),
+        None => render::SampleBudget::Auto { target_frame_time: AUTO_FRAME_TIME },
+    });
+    renderer.set_tone_mapping(args.tone_mapping);
+    renderer.set_exposure(args.exposure);
+    renderer.set_max_bounces(args.max_bounces);
+    renderer.set_russian_roulette(args.russian_roulette());
+    renderer.set_sampler(args.sampler);
+    let mut camera = scene.camera;
+    // Where the cursor was last seen in the window, if it has been.
+    let mut cursor_position: Option<PhysicalPosition<f64>> = None;
+    let mut movement = camera::Movement::default();
     let mut last_frame_time = Instant::now();
+    // Whether the time of day of the physical sky passes by itself.
+    let mut animate_sky = false;
     event_loop.run(|event, control_handle| {
         control_handle.set_control_flow(ControlFlow::Poll);
         match event {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => control_handle.exit(),
+                // A minimized window reports a size of zero, which a surface cannot have. Keep
+                // the old size until it is restored.
+                WindowEvent::Resized(size) if size.width > 0 && size.height > 0 => {
+                    surface_config.width = size.width;
+                    surface_config.height = size.height;
+                    surface.configure(&device, &surface_config);
+                    renderer.resize(size.width, size.height);
+                }
+                WindowEvent::CursorMoved { position, .. } => cursor_position = Some(position),
+                // Key releases go to whichever window has the focus.
+                WindowEvent::Focused(false) => movement.release_all(),
+                // Focus on whatever is under the cursor. The cursor is locked to the window for
+                // mouse look, in which case it may not report moves and the centre is used.
+                WindowEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    button: MouseButton::Left,
+                    ..
+                } => {
+                    let (width, height) = (surface_config.width, surface_config.height);
+                    let centre = PhysicalPosition::new(width as f64 / 2., height as f64 / 2.);
+                    let position = cursor_position.unwrap_or(centre);
+                    let ray =
+                        camera.ray_through(position.x as f32, position.y as f32, width, height);
+                    if let Some((_, distance)) = ray.and_then(|ray| scene.intersect(&ray)) {
+                        camera.set_focus_distance(distance);
+                        renderer.reset_samples();
+                        println!("focus distance {:.3}", camera.focus_distance());
+                    }
+                }
                 WindowEvent::RedrawRequested => {
                     // Wait for the next available frame buffer.
                 
                     
-                    let frame: wgpu::SurfaceTexture = surface
-                        .get_current_texture()
-                        .expect("failed to get current texture");
+                    let frame: wgpu::SurfaceTexture = match surface.get_current_texture() {
+                        Ok(frame) => frame,
+                        // The surface can go out of date while the window is being resized,
+                        // before the resize event arrives.
+                        Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                            surface.configure(&device, &surface_config);
+                            window.request_redraw();
+                            return;
+                        }
+                        Err(error) => panic!("failed to get current texture: {}", error),
+                    };
+
+                    // Move by the time since the last frame, so that the speed does not depend
+                    // on the frame rate.
+                    let now = Instant::now();
+                    let elapsed = now.duration_since(last_frame_time).min(MAX_MOVEMENT_TIME);
+                    last_frame_time = now;
+                    if camera.fly(&movement, args.speed * elapsed.as_secs_f32()) {
+                        renderer.reset_samples();
+                    }
+                    if let Some(mut sky) = renderer.sky().copied().filter(|_| animate_sky) {
+                        sky.advance(SKY_ANIMATION_SPEED * elapsed.as_secs_f32());
+                        renderer.set_sky(sky);
+                    }
 
                     // TODO: draw frame
                     let render_target = frame.texture.create_view(&wgpu::TextureViewDescriptor::default());
@@ -59,11 +322,139 @@ async fn main() -> Result<()> {
                     frame.present();
                     window.request_redraw();
                 }
-                    WindowEvent::KeyboardInput { device_id, event, is_synthetic } => {
-                    // TODO: handle keyboard input for camera translation
-                    camera.translate(event);
-                    renderer.reset_samples();
-                    
+                    WindowEvent::KeyboardInput { event, .. } => {
+                    if event.state == ElementState::Pressed && !event.repeat {
+                        if let PhysicalKey::Code(KeyCode::KeyN) = event.physical_key {
+                            renderer.set_light_sampling(!renderer.light_sampling());
+                            println!(
+                                "light sampling {}",
+                                if renderer.light_sampling() { "on" } else { "off" }
+                            );
+                        }
+                        if let PhysicalKey::Code(KeyCode::KeyR) = event.physical_key {
+                            renderer.set_russian_roulette(match renderer.russian_roulette() {
+                                Some(_) => None,
+                                None => Some(args.roulette_depth),
+                            });
+                            let max_bounces = renderer.max_bounces();
+                            match renderer.russian_roulette() {
+                                Some(depth) => println!(
+                                    "russian roulette after {} of at most {} bounces",
+                                    depth,
+                                    max_bounces
+                                ),
+                                None => {
+                                    println!("russian roulette off, at most {} bounces", max_bounces)
+                                }
+                            }
+                        }
+                        if let PhysicalKey::Code(KeyCode::KeyI) = event.physical_key {
+                            match renderer.read_path_statistics() {
+                                Ok(statistics) => println!("{}", statistics),
+                                Err(error) => {
+                                    eprintln!("failed to read the path statistics: {:#}", error)
+                                }
+                            }
+                        }
+                        if let PhysicalKey::Code(KeyCode::KeyO) = event.physical_key {
+                            camera.set_orbit(!camera.orbit());
+                            println!("orbit mode {}", if camera.orbit() { "on" } else { "off" });
+                        }
+                        if let PhysicalKey::Code(KeyCode::KeyJ) = event.physical_key {
+                            if renderer.sky().is_some() {
+                                animate_sky = !animate_sky;
+                                println!(
+                                    "time of day {}",
+                                    if animate_sky { "animated" } else { "paused" }
+                                );
+                            }
+                        }
+                        if let PhysicalKey::Code(KeyCode::KeyG) = event.physical_key {
+                            renderer.set_sampler(renderer.sampler().next());
+                            println!("sampler {}", renderer.sampler());
+                        }
+                        if let PhysicalKey::Code(KeyCode::KeyT) = event.physical_key {
+                            renderer.set_tone_mapping(renderer.tone_mapping().next());
+                            println!("tone mapping {}", renderer.tone_mapping());
+                        }
+                        if let PhysicalKey::Code(KeyCode::F12) = event.physical_key {
+                            match output::save_screenshot(&renderer) {
+                                Ok([png, exr]) => println!(
+                                    "saved {} and {} ({} samples per pixel)",
+                                    png.display(),
+                                    exr.display(),
+                                    renderer.sample_count()
+                                ),
+                                Err(error) => eprintln!("failed to save a screenshot: {:#}", error),
+                            }
+                        }
+                    }
+                    // Exposure, environment and time of day keys repeat while held.
+                    if event.state == ElementState::Pressed {
+                        let step = match event.physical_key {
+                            PhysicalKey::Code(KeyCode::Minus) => -EXPOSURE_STEP,
+                            PhysicalKey::Code(KeyCode::Equal) => EXPOSURE_STEP,
+                            _ => 0.,
+                        };
+                        if step != 0. {
+                            renderer.set_exposure(renderer.exposure() + step);
+                            println!("exposure {:+.1} EV", renderer.exposure());
+                        }
+                        let rotation_step = match event.physical_key {
+                            PhysicalKey::Code(KeyCode::ArrowLeft) => -ENVIRONMENT_ROTATION_STEP,
+                            PhysicalKey::Code(KeyCode::ArrowRight) => ENVIRONMENT_ROTATION_STEP,
+                            _ => 0.,
+                        };
+                        let intensity_step = match event.physical_key {
+                            PhysicalKey::Code(KeyCode::ArrowDown) => -ENVIRONMENT_INTENSITY_STEP,
+                            PhysicalKey::Code(KeyCode::ArrowUp) => ENVIRONMENT_INTENSITY_STEP,
+                            _ => 0.,
+                        };
+                        let time_step = match event.physical_key {
+                            PhysicalKey::Code(KeyCode::KeyK) => -SKY_TIME_STEP,
+                            PhysicalKey::Code(KeyCode::KeyL) => SKY_TIME_STEP,
+                            _ => 0.,
+                        };
+                        if let Some(mut sky) = renderer.sky().copied().filter(|_| time_step != 0.) {
+                            sky.advance(time_step);
+                            renderer.set_sky(sky);
+                            println!(
+                                "sun elevation {:.1} degrees, azimuth {:.1} degrees, turbidity {}",
+                                sky.sun_elevation(),
+                                sky.sun_azimuth(),
+                                sky.turbidity()
+                            );
+                        }
+                        if rotation_step != 0. || intensity_step != 0. {
+                            let rotation = renderer.environment_rotation() + rotation_step;
+                            renderer.set_environment_rotation(rotation);
+                            let intensity = renderer.environment_intensity();
+                            renderer.set_environment_intensity(intensity * intensity_step.exp2());
+                            println!(
+                                "environment rotation {:.0} degrees, intensity {:.3}",
+                                renderer.environment_rotation(),
+                                renderer.environment_intensity()
+                            );
+                        }
+                    }
+                    if event.state == ElementState::Pressed && camera.adjust_lens(&event) {
+                        renderer.reset_samples();
+                        println!(
+                            "aperture radius {:.3}, {} blades, focus distance {:.3}",
+                            camera.aperture_radius(),
+                            camera.aperture_blades(),
+                            camera.focus_distance()
+                        );
+                    }
+                    if event.state == ElementState::Pressed && camera.adjust_projection(&event) {
+                        renderer.reset_samples();
+                        println!(
+                            "{} projection, vertical field of view {:.1} degrees",
+                            camera.projection(),
+                            camera.vfov()
+                        );
+                    }
+                    movement.handle_key(&event);
                 }
 
                 _ => (),
@@ -74,14 +465,18 @@ async fn main() -> Result<()> {
                         MouseScrollDelta::PixelDelta(delta) => 0.001 * delta.y as f32,
                         MouseScrollDelta::LineDelta(_, y) => y * 0.1,
                     };
-                    camera.zoom(delta);
-                    renderer.reset_samples();
+                    if delta != 0. {
+                        camera.zoom(delta);
+                        renderer.reset_samples();
+                    }
                 }
                 DeviceEvent::MouseMotion { delta } => {
                     let (dx, dy) = delta;
                     let sensitivity = 0.002;
-                    camera.rotate(-dx as f32 * sensitivity, dy as f32 * sensitivity);
-                    renderer.reset_samples();
+                    if dx != 0. || dy != 0. {
+                        camera.rotate(-dx as f32 * sensitivity, dy as f32 * sensitivity);
+                        renderer.reset_samples();
+                    }
 
                    //s window.set_cursor_position(LogicalPosition::new(0.0, 0.0)).unwrap();
                 },
@@ -94,9 +489,12 @@ async fn main() -> Result<()> {
 }
 
 async fn connect_to_gpu(
-    window: &Window
-) -> Result<(wgpu::Device, wgpu::Queue, wgpu::Surface)> {
-    use wgpu::TextureFormat::{Bgra8Unorm, Rgba8Unorm};
+    window: &Window,
+    hdr: bool,
+) -> Result<(wgpu::Device, wgpu::Queue, wgpu::Surface, wgpu::SurfaceConfiguration)> {
+    use wgpu::TextureFormat::{
+        Bgra8Unorm, Bgra8UnormSrgb, Rgb10a2Unorm, Rgba16Float, Rgba8Unorm, Rgba8UnormSrgb,
+    };
 
     // Create an "instance" of wgpu. This is the entry-point to the API.
     let instance = wgpu::Instance::default();
@@ -125,12 +523,21 @@ async fn connect_to_gpu(
 
     // Configure the texture memory backing the surface. Our renderer will draw to a surface
     // texture every frame.
+    // sRGB formats are preferred since they encode colours more accurately than the gamma curve
+    // applied by the shader otherwise. With `--hdr`, a float or 10-bit format is tried first.
     let caps = surface.get_capabilities(&adapter);
-    let format = caps
-        .formats
+    let sdr_formats = [Bgra8UnormSrgb, Rgba8UnormSrgb, Bgra8Unorm, Rgba8Unorm];
+    let hdr_formats = [Rgba16Float, Rgb10a2Unorm];
+    let format = hdr
+        .then_some(hdr_formats)
         .into_iter()
-        .find(|it| matches!(it, Rgba8Unorm | Bgra8Unorm))
-        .context("could not find preferred texture format (Rgba8Unorm or Bgra8Unorm)")?;
+        .flatten()
+        .chain(sdr_formats)
+        .find(|format| caps.formats.contains(format))
+        .with_context(|| format!("no usable surface format among {:?}", caps.formats))?;
+    if hdr && !hdr_formats.contains(&format) {
+        println!("HDR output is not supported by the display, using {:?}", format);
+    }
     let size = window.inner_size();
     let config = wgpu::SurfaceConfiguration {
         usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -144,6 +551,6 @@ async fn connect_to_gpu(
     };
     surface.configure(&device, &config);
 
-    Ok((device, queue, surface))
+    Ok((device, queue, surface, config))
 }
 
@@ -110,6 +110,45 @@ impl Vec4 {
             0.0,
         ])
     }
+
+    /// Component-wise minimum.
+    pub fn min(&self, rhs: &Vec4) -> Vec4 {
+        Vec4::new(self.x().min(rhs.x()), self.y().min(rhs.y()), self.z().min(rhs.z()))
+    }
+
+    /// Component-wise maximum.
+    pub fn max(&self, rhs: &Vec4) -> Vec4 {
+        Vec4::new(self.x().max(rhs.x()), self.y().max(rhs.y()), self.z().max(rhs.z()))
+    }
+
+    pub fn to_array(self) -> [f32; 3] {
+        [self.x(), self.y(), self.z()]
+    }
+}
+
+impl From<[f32; 3]> for Vec4 {
+    fn from(v: [f32; 3]) -> Vec4 {
+        Vec4::new(v[0], v[1], v[2])
+    }
+}
+
+impl ops::Index<usize> for Vec4 {
+    type Output = f32;
+    fn index(&self, index: usize) -> &f32 {
+        &self.0[index]
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Ray {
+    pub origin: Vec4,
+    pub direction: Vec4,
+}
+
+impl Ray {
+    pub fn new(origin: Vec4, direction: Vec4) -> Ray {
+        Ray { origin, direction }
+    }
 }
 
 impl_binary_op!(Add : add => (lhs: Vec4, rhs: Vec4) -> Vec4 {
@@ -0,0 +1,80 @@
+use {
+    crate::math::Vec4,
+    anyhow::{Context, Result},
+    std::path::Path,
+};
+
+/// A triangle mesh corresponding to one object or group of a Wavefront OBJ file.
+pub struct ObjGroup {
+    pub name: String,
+    pub positions: Vec<Vec4>,
+    pub normals: Vec<Vec4>,
+    pub triangles: Vec<[u32; 3]>,
+}
+
+/// Loads all groups of an OBJ file. Faces are triangulated and every group gets one normal per
+/// vertex. Groups without normals in the file get smooth normals computed from their faces.
+pub fn load_obj(path: &Path) -> Result<Vec<ObjGroup>> {
+    let options = tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ..Default::default()
+    };
+    // Materials from .mtl files are ignored, materials are assigned by the scene file instead.
+    let (models, _) = tobj::load_obj(path, &options)
+        .with_context(|| format!("failed to load mesh {}", path.display()))?;
+
+    let groups = models
+        .into_iter()
+        .map(|model| {
+            let mesh = model.mesh;
+            let positions: Vec<Vec4> = mesh
+                .positions
+                .chunks_exact(3)
+                .map(|p| Vec4::new(p[0], p[1], p[2]))
+                .collect();
+            let triangles: Vec<[u32; 3]> = mesh
+                .indices
+                .chunks_exact(3)
+                .map(|t| [t[0], t[1], t[2]])
+                .collect();
+            let normals = if mesh.normals.len() == mesh.positions.len() {
+                mesh.normals
+                    .chunks_exact(3)
+                    .map(|n| Vec4::new(n[0], n[1], n[2]).normalized())
+                    .collect()
+            } else {
+                smooth_normals(&positions, &triangles)
+            };
+            ObjGroup {
+                name: model.name,
+                positions,
+                normals,
+                triangles,
+            }
+        })
+        .collect();
+    Ok(groups)
+}
+
+// Averages the normals of the faces around each vertex, weighted by face area.
+fn smooth_normals(positions: &[Vec4], triangles: &[[u32; 3]]) -> Vec<Vec4> {
+    let mut normals = vec![Vec4::zero(); positions.len()];
+    for [a, b, c] in triangles.iter().map(|t| t.map(|i| i as usize)) {
+        // The length of the cross product is twice the area of the triangle.
+        let n = (positions[b] - positions[a]).cross(&(positions[c] - positions[a]));
+        normals[a] += n;
+        normals[b] += n;
+        normals[c] += n;
+    }
+    normals
+        .into_iter()
+        .map(|n| {
+            if n.length_squared() > 0. {
+                n.normalized()
+            } else {
+                n
+            }
+        })
+        .collect()
+}
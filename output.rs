@@ -0,0 +1,244 @@
+//! Writing rendered images to files, for `--output` and for screenshots of the window.
+
+use {
+    crate::{
+        environment,
+        render::PathTracer,
+        tonemap::{self, ToneMapping},
+    },
+    anyhow::{bail, Context, Result},
+    exr::prelude::{
+        AttributeValue, Encoding, Image as ExrImage, Layer, LayerAttributes, SpecificChannels,
+        Text, Vec2, WritableImage,
+    },
+    std::{
+        fs::File,
+        io::{BufWriter, Read},
+        path::{Path, PathBuf},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+// Name of the software in the metadata of written files.
+const SOFTWARE: &str = "path-tracer";
+// The first bytes of every OpenEXR file.
+const EXR_MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+/// A file format that images can be written in, given by the extension of the file name.
+pub enum Format {
+    // 8-bit, with the same display transform as the window.
+    Png,
+    // 32-bit float of the raw average radiance.
+    Exr,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Format> {
+        let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("png") => Ok(Format::Png),
+            Some("exr") => Ok(Format::Exr),
+            _ => bail!("{}: unsupported output format, expected .png or .exr", path.display()),
+        }
+    }
+}
+
+/// The average radiance accumulated by a path tracer, as RGB triples in row major order.
+pub struct Image {
+    width: u32,
+    height: u32,
+    radiance: Vec<[f32; 3]>,
+    samples_per_pixel: u32,
+    created: SystemTime,
+}
+
+impl Image {
+    /// Reads back the samples accumulated by `renderer` so far.
+    pub fn capture(renderer: &PathTracer) -> Result<Image> {
+        let (width, height) = renderer.size();
+        Ok(Image {
+            width,
+            height,
+            radiance: renderer.read_radiance()?,
+            samples_per_pixel: renderer.sample_count(),
+            created: SystemTime::now(),
+        })
+    }
+
+    /// Reads an OpenEXR image, such as one written by `write`, to compare renders with. Its sample
+    /// count is unknown and left at 0.
+    pub fn read(path: &Path) -> Result<Image> {
+        let mut magic = Vec::with_capacity(EXR_MAGIC.len());
+        File::open(path)
+            .and_then(|file| file.take(EXR_MAGIC.len() as u64).read_to_end(&mut magic))
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        if magic != EXR_MAGIC {
+            bail!("{}: the reference must be an OpenEXR file", path.display());
+        }
+        let map = environment::read_exr(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(Image {
+            width: map.width,
+            height: map.height,
+            radiance: map.texels.iter().map(|&[r, g, b, _]| [r, g, b]).collect(),
+            samples_per_pixel: 0,
+            created: SystemTime::now(),
+        })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// The root mean square error of the radiance against `reference`, over all pixels and colour
+    /// components.
+    pub fn rmse(&self, reference: &Image) -> Result<f64> {
+        if self.size() != reference.size() {
+            bail!("cannot compare a {:?} image with a {:?} one", self.size(), reference.size());
+        }
+        let squared_error: f64 = self
+            .radiance
+            .iter()
+            .zip(&reference.radiance)
+            .flat_map(|(a, b)| (0..3).map(move |i| (a[i] as f64 - b[i] as f64).powi(2)))
+            .sum();
+        Ok((squared_error / (3 * self.radiance.len()).max(1) as f64).sqrt())
+    }
+
+    /// Writes the image to `path`, with the sample count and the creation time as metadata. 8-bit
+    /// formats are displayed with `tone_mapping` and an exposure of `ev` stops.
+    pub fn write(&self, path: &Path, tone_mapping: ToneMapping, ev: f32) -> Result<()> {
+        match Format::from_path(path)? {
+            Format::Png => self.write_png(path, tone_mapping, ev),
+            Format::Exr => self.write_exr(path),
+        }
+        .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    fn write_png(&self, path: &Path, tone_mapping: ToneMapping, ev: f32) -> Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_source_gamma(png::ScaledFloat::new(1. / 2.2));
+        // Keywords from section 11.3.4.2 of the PNG specification, except the sample count.
+        encoder.add_text_chunk("Software".into(), SOFTWARE.into())?;
+        let [year, month, day, hour, minute, second] = utc_date_time(self.created);
+        let creation_time = format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, hour, minute, second
+        );
+        encoder.add_text_chunk("Creation Time".into(), creation_time)?;
+        encoder.add_text_chunk("Samples per pixel".into(), self.samples_per_pixel.to_string())?;
+        encoder.add_text_chunk(
+            "Comment".into(),
+            format!("tone mapping {}, exposure {:+} EV", tone_mapping, ev),
+        )?;
+
+        let pixels: Vec<u8> = self
+            .radiance
+            .iter()
+            .flat_map(|&c| tonemap::display_transform(c, tone_mapping, ev))
+            .collect();
+        encoder.write_header()?.write_image_data(&pixels)?;
+        Ok(())
+    }
+
+    fn write_exr(&self, path: &Path) -> Result<()> {
+        let width = self.width as usize;
+        let channels = SpecificChannels::rgb(|Vec2(x, y)| {
+            let [r, g, b] = self.radiance[y * width + x];
+            (r, g, b)
+        });
+        let [year, month, day, hour, minute, second] = utc_date_time(self.created);
+        let mut attributes = LayerAttributes {
+            software_name: Some(Text::from(SOFTWARE)),
+            capture_date: Some(Text::from(
+                format!(
+                    "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+                    year, month, day, hour, minute, second
+                )
+                .as_str(),
+            )),
+            utc_offset: Some(0.),
+            ..LayerAttributes::default()
+        };
+        attributes.other.insert(
+            Text::from("samplesPerPixel"),
+            AttributeValue::I32(self.samples_per_pixel.try_into().unwrap_or(i32::MAX)),
+        );
+        let size = (width, self.height as usize);
+        let layer = Layer::new(size, attributes, Encoding::FAST_LOSSLESS, channels);
+        ExrImage::from_layer(layer).write().to_file(path)?;
+        Ok(())
+    }
+}
+
+/// Saves the image accumulated by `renderer` as a tone mapped PNG and a linear EXR, named after the
+/// current time, in the working directory. Screenshots taken within the same second are numbered
+/// rather than overwritten. Returns the paths of both files.
+pub fn save_screenshot(renderer: &PathTracer) -> Result<[PathBuf; 2]> {
+    let image = Image::capture(renderer)?;
+    let [year, month, day, hour, minute, second] = utc_date_time(image.created);
+    let name = format!(
+        "screenshot-{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, hour, minute, second
+    );
+    let paths = (1..)
+        .map(|n| {
+            let name = if n == 1 { name.clone() } else { format!("{}-{}", name, n) };
+            ["png", "exr"].map(|extension| PathBuf::from(format!("{}.{}", name, extension)))
+        })
+        .find(|paths| paths.iter().all(|path| !path.exists()))
+        .expect("ran out of screenshot names");
+    for path in &paths {
+        image.write(path, renderer.tone_mapping(), renderer.exposure())?;
+    }
+    Ok(paths)
+}
+
+// Splits a time into the year, month, day, hour, minute and second in UTC, using the algorithm of
+// `civil_from_days` in http://howardhinnant.github.io/date_algorithms.html
+fn utc_date_time(time: SystemTime) -> [u64; 6] {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+
+    // Days since 0000-03-01, so that leap days are at the end of the year.
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    [year, month, day, seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60]
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::utc_date_time,
+        std::time::{Duration, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn utc_date_time_of_known_times() {
+        let cases = [
+            (0, [1970, 1, 1, 0, 0, 0]),
+            (946684799, [1999, 12, 31, 23, 59, 59]),
+            // Leap days, including one of a year divisible by 400.
+            (951782400, [2000, 2, 29, 0, 0, 0]),
+            (1709210096, [2024, 2, 29, 12, 34, 56]),
+            // 2100 is divisible by 100 but not by 400, so it is not a leap year.
+            (4107542399, [2100, 2, 28, 23, 59, 59]),
+            (4107542400, [2100, 3, 1, 0, 0, 0]),
+        ];
+        for (seconds, expected) in cases {
+            let time = UNIX_EPOCH + Duration::from_secs(seconds);
+            assert_eq!(utc_date_time(time), expected, "{} seconds after the epoch", seconds);
+        }
+    }
+}
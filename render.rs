@@ -1,24 +1,271 @@
+use anyhow::{Context, Result};
 use bytemuck::{Pod, Zeroable};
+use wgpu::util::DeviceExt;
 use crate::camera::{self, Camera, CameraUniforms};
+use crate::environment::{EnvironmentDistribution, EnvironmentMap};
 use crate::math::{Vec4};
+use crate::sampler::{self, Sampler};
+use crate::scene::Scene;
+use crate::sky::{Sky, SkyUniforms};
+use crate::tonemap::{self, ToneMapping};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How many samples per pixel each frame adds.
+#[derive(Debug, Copy, Clone)]
+pub enum SampleBudget {
+    Fixed(u32),
+    /// As many as fit in `target_frame_time`, starting from one whenever accumulation restarts so
+    /// that the view stays responsive while the camera moves.
+    Auto { target_frame_time: Duration },
+}
+
+// Upper bound of automatic budgets, so that a single frame cannot run long enough for the driver to
+// reset the GPU.
+const MAX_AUTO_SAMPLES_PER_FRAME: u32 = 64;
+
+/// Longest paths that can be traced, in bounces. Same as `MAX_BOUNCE_LIMIT` in shaders.wgsl.
+pub const MAX_BOUNCE_LIMIT: u32 = 64;
+pub const DEFAULT_MAX_BOUNCES: u32 = 13;
+// Number of 64-bit counters in the path statistics buffer, see `PATH_COUNTERS` in shaders.wgsl.
+const PATH_COUNTERS: usize = 2 * (MAX_BOUNCE_LIMIT as usize + 1);
+// Value of `Uniforms::roulette_depth` that disables Russian roulette.
+const NO_ROULETTE: u32 = u32::MAX;
+
 pub struct PathTracer {
     device: wgpu::Device,
     queue: wgpu::Queue,
-    pipeline: wgpu::RenderPipeline,
+    // Traces one sample per pixel into the radiance sums.
+    trace_pipeline: wgpu::ComputePipeline,
+    trace_bind_group_layout: wgpu::BindGroupLayout,
+    // Draws the average of the samples to the target.
+    display_pipeline: wgpu::RenderPipeline,
+    display_bind_group_layout: wgpu::BindGroupLayout,
+    scene_buffers: SceneBuffers,
     uniforms: Uniforms,
     uniform_buffer: wgpu::Buffer,
-    bind_groups: [wgpu::BindGroup;2],
-    
+    // Running sums of the radiance samples. Each frame reads one and writes the other.
+    radiance_samples: [wgpu::Texture; 2],
+    trace_bind_groups: [wgpu::BindGroup;2],
+    // Bind group i displays the sum in `radiance_samples[i]`.
+    display_bind_groups: [wgpu::BindGroup; 2],
+    sample_budget: SampleBudget,
+    // When the previous frame was started, to measure the frame time of automatic budgets.
+    last_frame_start: Option<Instant>,
+    tone_mapping: ToneMapping,
+    // Exposure in stops.
+    exposure: f32,
+    sky: Option<Sky>,
+    // How the paths traced since accumulation restarted ended, see `PathStatistics`.
+    path_statistics: wgpu::Buffer,
+    sampler: Sampler,
+}
+
+/// How many of the paths traced since accumulation last restarted ended after each number of
+/// bounces, by escaping the scene, hitting a light, reaching the maximum number of bounces or
+/// being terminated by Russian roulette.
+pub struct PathStatistics {
+    /// The number of paths that ended after 0 to `MAX_BOUNCE_LIMIT` bounces.
+    pub paths: Vec<u64>,
+    /// The number of those that Russian roulette terminated.
+    pub terminated_by_roulette: Vec<u64>,
+}
+
+impl PathStatistics {
+    pub fn path_count(&self) -> u64 {
+        self.paths.iter().sum()
+    }
+
+    /// The average number of bounces per path.
+    pub fn mean_bounces(&self) -> f64 {
+        let bounces: u64 = self.paths.iter().enumerate().map(|(i, &n)| i as u64 * n).sum();
+        bounces as f64 / self.path_count().max(1) as f64
+    }
+
+    /// The fraction of the paths that Russian roulette terminated.
+    pub fn roulette_fraction(&self) -> f64 {
+        self.terminated_by_roulette.iter().sum::<u64>() as f64 / self.path_count().max(1) as f64
+    }
+}
+
+impl fmt::Display for PathStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "bounces        paths  terminated by roulette")?;
+        for (bounces, (&paths, &roulette)) in
+            self.paths.iter().zip(&self.terminated_by_roulette).enumerate()
+        {
+            if paths > 0 {
+                writeln!(f, "{:7} {:12} {:23}", bounces, paths, roulette)?;
+            }
+        }
+        write!(
+            f,
+            "{} paths, {:.2} bounces on average, {:.1}% terminated by roulette",
+            self.path_count(),
+            self.mean_bounces(),
+            100. * self.roulette_fraction()
+        )
+    }
+}
+
+// Same as `WORKGROUP_SIZE` in shaders.wgsl.
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Read-only storage buffers holding the scene geometry and materials, the environment map with
+/// the distribution that it is sampled from, and the blue noise texture of `Sampler::BlueNoise`.
+struct SceneBuffers {
+    spheres: wgpu::Buffer,
+    materials: wgpu::Buffer,
+    vertices: wgpu::Buffer,
+    triangles: wgpu::Buffer,
+    bvh_nodes: wgpu::Buffer,
+    bvh_primitives: wgpu::Buffer,
+    lights: wgpu::Buffer,
+    environment_map: wgpu::Texture,
+    environment_cdf: wgpu::Texture,
+    blue_noise: wgpu::Texture,
+}
+
+impl SceneBuffers {
+    fn new(device: &wgpu::Device, queue: &wgpu::Queue, scene: &Scene) -> SceneBuffers {
+        let environment_map = scene.environment.as_ref().map(|environment| &environment.map);
+        let (environment_map, environment_cdf) =
+            create_environment_textures(device, queue, environment_map);
+        SceneBuffers {
+            spheres: create_storage_buffer(device, "spheres", &scene.spheres),
+            materials: create_storage_buffer(device, "materials", &scene.materials),
+            vertices: create_storage_buffer(device, "vertices", &scene.vertices),
+            triangles: create_storage_buffer(device, "triangles", &scene.triangles),
+            bvh_nodes: create_storage_buffer(device, "bvh nodes", &scene.bvh.nodes),
+            bvh_primitives: create_storage_buffer(device, "bvh primitives", &scene.bvh.primitives),
+            lights: create_storage_buffer(device, "lights", &scene.lights),
+            environment_map,
+            environment_cdf,
+            blue_noise: device.create_texture_with_data(
+                queue,
+                &texture_descriptor(
+                    "blue noise",
+                    sampler::BLUE_NOISE_SIZE,
+                    sampler::BLUE_NOISE_SIZE,
+                    wgpu::TextureFormat::Rg32Uint,
+                ),
+                wgpu::util::TextureDataOrder::LayerMajor,
+                bytemuck::cast_slice(&sampler::blue_noise_texture()),
+            ),
+        }
+    }
+}
+
+// Texture bindings cannot be left empty either, so scenes without an environment map get a single
+// black texel that the shader never reads. Maps larger than the device supports are downsampled,
+// including the column that the CDF texture has in addition to the map.
+fn create_environment_textures(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    map: Option<&EnvironmentMap>,
+) -> (wgpu::Texture, wgpu::Texture) {
+    let black = EnvironmentMap { width: 1, height: 1, texels: vec![[0.; 4]] };
+    let mut map = std::borrow::Cow::Borrowed(map.unwrap_or(&black));
+    let max_size = device.limits().max_texture_dimension_2d;
+    while map.width + 1 > max_size || map.height > max_size {
+        map = std::borrow::Cow::Owned(map.downsampled());
+        println!(
+            "downsampled the environment map to {}x{} to fit the texture size limit",
+            map.width, map.height
+        );
+    }
+    let distribution = EnvironmentDistribution::new(&map);
+    let texels = device.create_texture_with_data(
+        queue,
+        &texture_descriptor(
+            "environment map",
+            map.width,
+            map.height,
+            wgpu::TextureFormat::Rgba32Float,
+        ),
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&map.texels),
+    );
+    let cdf = device.create_texture_with_data(
+        queue,
+        &texture_descriptor(
+            "environment cdf",
+            map.width + 1,
+            map.height,
+            wgpu::TextureFormat::R32Float,
+        ),
+        wgpu::util::TextureDataOrder::LayerMajor,
+        bytemuck::cast_slice(&distribution.texels()),
+    );
+    (texels, cdf)
+}
+
+fn texture_descriptor(
+    label: &str,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+) -> wgpu::TextureDescriptor<'_> {
+    wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    }
+}
+
+// Storage buffer bindings cannot be empty, so an empty slice is uploaded as a single zeroed
+// element. Padding elements are never referenced by the BVH, and a zeroed light has no power.
+fn create_storage_buffer<T: Pod>(device: &wgpu::Device, label: &str, contents: &[T]) -> wgpu::Buffer {
+    let zeroed = [T::zeroed()];
+    let contents = if contents.is_empty() { &zeroed[..] } else { contents };
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(label),
+        contents: bytemuck::cast_slice(contents),
+        usage: wgpu::BufferUsages::STORAGE,
+    })
 }
 
 #[derive(Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
 struct Uniforms {
     camera: CameraUniforms,
+    sky: SkyUniforms,
     width: u32,
     height: u32,
     frame_count: u32,
-    _padding2: u32,
+    // Non-zero if direct lighting is estimated by sampling the lights (next-event estimation).
+    light_sampling: u32,
+    // Number of samples per pixel traced by this frame.
+    samples_per_frame: u32,
+    // Number of samples per pixel in the sum once this frame has been traced.
+    sample_count: u32,
+    tone_mapping: u32,
+    // Factor that radiance is scaled by before tone mapping.
+    exposure: f32,
+    // Non-zero if the environment map replaces the sky gradient.
+    environment: u32,
+    // Rotation of the environment around the y axis in radians, counterclockwise seen from above.
+    environment_rotation: f32,
+    // Factor that the radiance of the environment is scaled by.
+    environment_intensity: f32,
+    // Non-zero if the physical sky in `sky` replaces the sky gradient.
+    physical_sky: u32,
+    // Longest path, in bounces.
+    max_bounces: u32,
+    // Number of bounces after which paths are subject to Russian roulette, or `NO_ROULETTE`.
+    roulette_depth: u32,
+    // How the numbers of the samples are generated, a `Sampler`.
+    sample_generator: u32,
+    _padding: u32,
 }
 fn create_sample_texture(device: &wgpu::Device, width: u32, height: u32) -> [wgpu::Texture; 2] {
     let desc = &wgpu::TextureDescriptor {
@@ -32,57 +279,102 @@ fn create_sample_texture(device: &wgpu::Device, width: u32, height: u32) -> [wgp
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
         format: wgpu::TextureFormat::Rgba32Float,
-        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING
+            | wgpu::TextureUsages::STORAGE_BINDING
+            | wgpu::TextureUsages::COPY_SRC,
         view_formats: &[],
     };
     [device.create_texture(&desc), device.create_texture(&desc)]
 }
 
-fn create_pipeline_layout(
+// Whether colours written to a target of this format must be gamma encoded by the shader. sRGB
+// formats are encoded by the hardware when they are written, and float formats hold linear values.
+fn encodes_gamma_in_shader(format: wgpu::TextureFormat) -> bool {
+    use wgpu::TextureFormat::{Rgba16Float, Rgba32Float};
+    !format.is_srgb() && !matches!(format, Rgba16Float | Rgba32Float)
+}
+
+fn create_trace_pipeline(
     device: &wgpu::Device,
     shader_module: &wgpu::ShaderModule,
-) -> (wgpu::RenderPipeline, wgpu::BindGroupLayout) {
+) -> (wgpu::ComputePipeline, wgpu::BindGroupLayout) {
     let bind_group_layout =
         device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: None,
+            label: Some("trace"),
             entries: &[
+                uniform_layout_entry(wgpu::ShaderStages::COMPUTE),
+                sum_texture_layout_entry(1, wgpu::ShaderStages::COMPUTE),
                 wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::StorageTexture {
+                        access: wgpu::StorageTextureAccess::WriteOnly,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        view_dimension: wgpu::TextureViewDimension::D2,
                     },
                     count: None,
                 },
+                storage_buffer_layout_entry(3),
+                storage_buffer_layout_entry(4),
+                storage_buffer_layout_entry(5),
+                storage_buffer_layout_entry(6),
+                storage_buffer_layout_entry(7),
+                storage_buffer_layout_entry(8),
+                storage_buffer_layout_entry(9),
+                sum_texture_layout_entry(10, wgpu::ShaderStages::COMPUTE),
+                sum_texture_layout_entry(11, wgpu::ShaderStages::COMPUTE),
+                storage_buffer_layout_entry_with_access(12, false),
                 wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    binding: 13,
+                    visibility: wgpu::ShaderStages::COMPUTE,
                     ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        sample_type: wgpu::TextureSampleType::Uint,
                         view_dimension: wgpu::TextureViewDimension::D2,
                         multisampled: false,
                     },
                     count: None,
                 },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::StorageTexture {
-                        access: wgpu::StorageTextureAccess::WriteOnly,
-                        format: wgpu::TextureFormat::Rgba32Float,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                    },
-                    count: None,
-                },
             ],
         });
-   let pipeline =  device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
         label: Some("path tracer"),
+        layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("path tracer pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        })),
+        module: shader_module,
+        entry_point: Some("path_tracer_cs"),
+        compilation_options: wgpu::PipelineCompilationOptions::default(),
+        cache: None,
+    });
+    (pipeline, bind_group_layout)
+}
+
+// Binding of the radiance sum that the display pass reads, `radiance_sum` in shaders.wgsl. It is
+// kept apart from the bindings of the trace pass, which shares the shader module.
+const RADIANCE_SUM_BINDING: u32 = 32;
+
+fn create_display_pipeline(
+    device: &wgpu::Device,
+    shader_module: &wgpu::ShaderModule,
+    target_format: wgpu::TextureFormat,
+) -> (wgpu::RenderPipeline, wgpu::BindGroupLayout) {
+    let bind_group_layout =
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("display"),
+            entries: &[
+                uniform_layout_entry(wgpu::ShaderStages::FRAGMENT),
+                sum_texture_layout_entry(RADIANCE_SUM_BINDING, wgpu::ShaderStages::FRAGMENT),
+            ],
+        });
+    // Pipeline-overridable constants are passed as doubles, with bools as 0 or 1.
+    let encode_gamma = if encodes_gamma_in_shader(target_format) { 1. } else { 0. };
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("display"),
         layout: Some(&device.create_pipeline_layout(
             &wgpu::PipelineLayoutDescriptor {
-                label: Some("path tracer pipeline layout"),
+                label: Some("display pipeline layout"),
                 bind_group_layouts: &[&bind_group_layout],
                 push_constant_ranges: &[],
             },
@@ -95,19 +387,22 @@ fn create_pipeline_layout(
         },
         vertex: wgpu::VertexState {
             module: shader_module,
-            entry_point: Some("path_tracer_vs"),
+            entry_point: Some("display_vs"),
             buffers: &[],
             compilation_options: wgpu::PipelineCompilationOptions::default(),
         },
         fragment: Some(wgpu::FragmentState {
             module: shader_module,
-            entry_point: Some("path_tracer_fs"),
+            entry_point: Some("display_fs"),
             targets: &[Some(wgpu::ColorTargetState {
-                format: wgpu::TextureFormat::Bgra8Unorm,
+                format: target_format,
                 blend: None,
                 write_mask: wgpu::ColorWrites::ALL,
             })],
-            compilation_options: wgpu::PipelineCompilationOptions::default(),
+            compilation_options: wgpu::PipelineCompilationOptions {
+                constants: &[("ENCODE_GAMMA", encode_gamma)],
+                ..Default::default()
+            },
         }),
         depth_stencil: None,
         multisample: wgpu::MultisampleState::default(),
@@ -117,8 +412,65 @@ fn create_pipeline_layout(
     (pipeline, bind_group_layout)
 }
 
+fn uniform_layout_entry(visibility: wgpu::ShaderStages) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding: 0,
+        visibility,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn sum_texture_layout_entry(
+    binding: u32,
+    visibility: wgpu::ShaderStages,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility,
+        ty: wgpu::BindingType::Texture {
+            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            view_dimension: wgpu::TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+fn storage_buffer_layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    storage_buffer_layout_entry_with_access(binding, true)
+}
+
+fn storage_buffer_layout_entry_with_access(
+    binding: u32,
+    read_only: bool,
+) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::COMPUTE,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
 impl PathTracer {
-    pub fn new(device: wgpu::Device, queue: wgpu::Queue) -> PathTracer {
+    /// Creates a path tracer that renders images with the size given by `target` and displays them
+    /// on targets with its format, such as the frames of a window surface.
+    pub fn new(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        scene: &Scene,
+        target: &wgpu::SurfaceConfiguration,
+    ) -> PathTracer {
+        let (width, height) = (target.width, target.height);
         device.on_uncaptured_error(Box::new(|error| {
             panic!("Aborting due to an error: {}", error);
         }));
@@ -126,13 +478,30 @@ impl PathTracer {
         // TODO: initialize GPU resources
         let camera:Vec4  = Vec4::all(0.);
         let shader_module = compile_shader_module(&device);
-        let (pipeline, layout) = create_pipeline_layout(&device, &shader_module);
+        let (trace_pipeline, trace_bind_group_layout) =
+            create_trace_pipeline(&device, &shader_module);
+        let (display_pipeline, display_bind_group_layout) =
+            create_display_pipeline(&device, &shader_module, target.format);
+        let environment = scene.environment.as_ref();
         let uniforms = Uniforms {
-            width: 800,
-            height: 600,
+            width,
+            height,
             frame_count: 0,
             camera : CameraUniforms::zeroed(),
-            _padding2: 0,
+            sky: scene.sky.as_ref().map_or(SkyUniforms::zeroed(), |sky| *sky.uniforms()),
+            light_sampling: 1,
+            samples_per_frame: 1,
+            sample_count: 0,
+            tone_mapping: ToneMapping::Linear as u32,
+            exposure: 1.,
+            environment: environment.is_some() as u32,
+            environment_rotation: environment.map_or(0., |e| e.rotation.to_radians()),
+            environment_intensity: environment.map_or(1., |e| e.intensity),
+            physical_sky: scene.sky.is_some() as u32,
+            max_bounces: DEFAULT_MAX_BOUNCES,
+            roulette_depth: NO_ROULETTE,
+            sample_generator: Sampler::Random as u32,
+            _padding: 0,
         };
         let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("uniforms"),
@@ -140,20 +509,354 @@ impl PathTracer {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
-        let r_samples = create_sample_texture(&device, 800, 600);
+        let radiance_samples = create_sample_texture(&device, width, height);
+        let scene_buffers = SceneBuffers::new(&device, &queue, scene);
+        let path_statistics = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("path statistics"),
+            size: (PATH_COUNTERS * std::mem::size_of::<u64>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
 
-    let bind_groups = create_bind_groups(r_samples, &device, &layout, &uniform_buffer);
-        
-        
+        let trace_bind_groups = create_trace_bind_groups(
+            &radiance_samples,
+            &device,
+            &trace_bind_group_layout,
+            &uniform_buffer,
+            &scene_buffers,
+            &path_statistics,
+        );
+        let display_bind_groups = create_display_bind_groups(
+            &radiance_samples,
+            &device,
+            &display_bind_group_layout,
+            &uniform_buffer,
+        );
 
-        PathTracer { device, queue, pipeline, uniforms, uniform_buffer, bind_groups }
+        PathTracer {
+            device,
+            queue,
+            trace_pipeline,
+            trace_bind_group_layout,
+            display_pipeline,
+            display_bind_group_layout,
+            scene_buffers,
+            uniforms,
+            uniform_buffer,
+            radiance_samples,
+            trace_bind_groups,
+            display_bind_groups,
+            sample_budget: SampleBudget::Fixed(1),
+            last_frame_start: None,
+            tone_mapping: ToneMapping::Linear,
+            exposure: 0.,
+            sky: scene.sky,
+            path_statistics,
+            sampler: Sampler::Random,
+        }
     }
 
     pub fn reset_samples(&mut self) {
         self.uniforms.frame_count = 0;
+        self.uniforms.sample_count = 0;
+    }
+
+    /// Width and height of the rendered image.
+    pub fn size(&self) -> (u32, u32) {
+        (self.uniforms.width, self.uniforms.height)
+    }
+
+    /// Number of samples per pixel accumulated so far.
+    pub fn sample_count(&self) -> u32 {
+        self.uniforms.sample_count
+    }
+
+    pub fn set_sample_budget(&mut self, budget: SampleBudget) {
+        self.sample_budget = budget;
+    }
+
+    // Chooses the number of samples of the next frame.
+    fn next_samples_per_frame(&mut self) -> u32 {
+        let now = Instant::now();
+        let last_frame_start = self.last_frame_start.replace(now);
+        let target_frame_time = match self.sample_budget {
+            SampleBudget::Fixed(samples) => return samples,
+            SampleBudget::Auto { target_frame_time } => target_frame_time,
+        };
+        if self.uniforms.sample_count == 0 {
+            return 1;
+        }
+        let current = self.uniforms.samples_per_frame;
+        let Some(last_frame_start) = last_frame_start else {
+            return current;
+        };
+
+        // Frames are pipelined, so the time between two frames is the time the GPU spends on one
+        // once the queue is full. It cannot drop below the refresh interval with vsync, which the
+        // target should be above. Changes are limited to a factor of two per frame, and small
+        // differences are ignored so that the count does not oscillate.
+        let ratio = target_frame_time.as_secs_f32() / (now - last_frame_start).as_secs_f32();
+        let next = if ratio > 1.1 {
+            (current as f32 * ratio.min(2.)) as u32 + 1
+        } else if ratio < 0.9 {
+            (current as f32 * ratio.max(0.5)) as u32
+        } else {
+            current
+        };
+        next.clamp(1, MAX_AUTO_SAMPLES_PER_FRAME)
+    }
+
+    /// Changes the size of the rendered image. The accumulated samples are discarded since they
+    /// no longer line up with the pixels.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.radiance_samples = create_sample_texture(&self.device, width, height);
+        self.trace_bind_groups = create_trace_bind_groups(
+            &self.radiance_samples,
+            &self.device,
+            &self.trace_bind_group_layout,
+            &self.uniform_buffer,
+            &self.scene_buffers,
+            &self.path_statistics,
+        );
+        self.display_bind_groups = create_display_bind_groups(
+            &self.radiance_samples,
+            &self.device,
+            &self.display_bind_group_layout,
+            &self.uniform_buffer,
+        );
+        self.uniforms.width = width;
+        self.uniforms.height = height;
+        self.reset_samples();
+    }
+
+    pub fn light_sampling(&self) -> bool {
+        self.uniforms.light_sampling != 0
+    }
+
+    /// Switches between next-event estimation combined with BSDF sampling through MIS, and pure
+    /// BSDF sampling. Accumulation restarts so that the two can be compared.
+    pub fn set_light_sampling(&mut self, enabled: bool) {
+        self.uniforms.light_sampling = enabled as u32;
+        self.reset_samples();
+    }
+
+    pub fn sampler(&self) -> Sampler {
+        self.sampler
+    }
+
+    /// Changes how the numbers of the samples are generated. Accumulation restarts so that the
+    /// samplers can be compared.
+    pub fn set_sampler(&mut self, sampler: Sampler) {
+        self.sampler = sampler;
+        self.uniforms.sample_generator = sampler as u32;
+        self.reset_samples();
+    }
+
+    pub fn tone_mapping(&self) -> ToneMapping {
+        self.tone_mapping
+    }
+
+    /// Changes how the image is mapped to the display. Unlike the other settings, this keeps the
+    /// accumulated samples since only the display pass is affected.
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping;
+        self.uniforms.tone_mapping = tone_mapping as u32;
     }
+
+    /// The exposure in EV stops, where 0 displays radiance unscaled.
+    pub fn exposure(&self) -> f32 {
+        self.exposure
+    }
+
+    pub fn set_exposure(&mut self, ev: f32) {
+        self.exposure = ev;
+        self.uniforms.exposure = tonemap::exposure_scale(ev);
+    }
+
+    /// The rotation of the environment around the y axis in degrees, counterclockwise seen from
+    /// above.
+    pub fn environment_rotation(&self) -> f32 {
+        self.uniforms.environment_rotation.to_degrees()
+    }
+
+    pub fn set_environment_rotation(&mut self, degrees: f32) {
+        self.uniforms.environment_rotation = (degrees % 360.).to_radians();
+        self.reset_samples();
+    }
+
+    /// The factor that the radiance of the environment map, or of the sky without one, is scaled
+    /// by.
+    pub fn environment_intensity(&self) -> f32 {
+        self.uniforms.environment_intensity
+    }
+
+    pub fn set_environment_intensity(&mut self, intensity: f32) {
+        self.uniforms.environment_intensity = intensity;
+        self.reset_samples();
+    }
+
+    pub fn max_bounces(&self) -> u32 {
+        self.uniforms.max_bounces
+    }
+
+    /// Sets the longest path in bounces, at most `MAX_BOUNCE_LIMIT`, and restarts accumulation.
+    pub fn set_max_bounces(&mut self, bounces: u32) {
+        self.uniforms.max_bounces = bounces.clamp(1, MAX_BOUNCE_LIMIT);
+        self.reset_samples();
+    }
+
+    /// The number of bounces after which paths are subject to Russian roulette, or None if they
+    /// always go on until they leave the scene, hit a light or reach the maximum number of bounces.
+    pub fn russian_roulette(&self) -> Option<u32> {
+        (self.uniforms.roulette_depth != NO_ROULETTE).then_some(self.uniforms.roulette_depth)
+    }
+
+    /// Enables Russian roulette after `depth` bounces, or disables it. Accumulation restarts so
+    /// that the two can be compared.
+    pub fn set_russian_roulette(&mut self, depth: Option<u32>) {
+        self.uniforms.roulette_depth = depth.unwrap_or(NO_ROULETTE);
+        self.reset_samples();
+    }
+
+    /// The physical sky, if the scene has one.
+    pub fn sky(&self) -> Option<&Sky> {
+        self.sky.as_ref()
+    }
+
+    /// Replaces the physical sky, for example after moving the sun, and restarts accumulation.
+    pub fn set_sky(&mut self, sky: Sky) {
+        self.uniforms.sky = *sky.uniforms();
+        self.uniforms.physical_sky = 1;
+        self.sky = Some(sky);
+        self.reset_samples();
+    }
+
+    /// Reads back the average of the radiance samples accumulated so far, as RGB triples in row
+    /// major order. This waits for all submitted frames to finish.
+    pub fn read_radiance(&self) -> Result<Vec<[f32; 3]>> {
+        let (width, height) = (self.uniforms.width, self.uniforms.height);
+        // Rows of a texture copy must be aligned to `COPY_BYTES_PER_ROW_ALIGNMENT`.
+        let texel_size = 4 * std::mem::size_of::<f32>() as u32;
+        let bytes_per_row =
+            (width * texel_size).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("radiance readback"),
+            size: bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        // Frame n reads the sum from texture n % 2 and writes the new one to the other texture.
+        let latest = &self.radiance_samples[(self.uniforms.frame_count as usize + 1) % 2];
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("read radiance"),
+            });
+        encoder.copy_texture_to_buffer(
+            latest.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            latest.size(),
+        );
+        self.submit_and_map(encoder, &buffer)
+            .context("failed to read back the radiance samples")?;
+
+        let scale = 1. / self.uniforms.sample_count.max(1) as f32;
+        let data = buffer.slice(..).get_mapped_range();
+        let texels: &[[f32; 4]] = bytemuck::cast_slice(&data);
+        let texels_per_row = (bytes_per_row / texel_size) as usize;
+        Ok(texels
+            .chunks(texels_per_row)
+            .flat_map(|row| &row[..width as usize])
+            .map(|&[r, g, b, _]| [r * scale, g * scale, b * scale])
+            .collect())
+    }
+
+    /// Reads back how the paths traced since accumulation last restarted ended. This waits for all
+    /// submitted frames to finish.
+    pub fn read_path_statistics(&self) -> Result<PathStatistics> {
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("path statistics readback"),
+            size: self.path_statistics.size(),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("read path statistics"),
+            });
+        encoder.copy_buffer_to_buffer(&self.path_statistics, 0, &buffer, 0, buffer.size());
+        self.submit_and_map(encoder, &buffer)
+            .context("failed to read back the path statistics")?;
+
+        let data = buffer.slice(..).get_mapped_range();
+        let words: &[[u32; 2]] = bytemuck::cast_slice(&data);
+        let counts: Vec<u64> =
+            words.iter().map(|&[lo, hi]| u64::from(hi) << 32 | u64::from(lo)).collect();
+        let (paths, terminated_by_roulette) = counts.split_at(PATH_COUNTERS / 2);
+        Ok(PathStatistics {
+            paths: paths.to_vec(),
+            terminated_by_roulette: terminated_by_roulette.to_vec(),
+        })
+    }
+
+    // Submits `encoder`, which copies into `buffer`, and maps `buffer` for reading once the copy
+    // and all work submitted before it have finished.
+    fn submit_and_map(&self, encoder: wgpu::CommandEncoder, buffer: &wgpu::Buffer) -> Result<()> {
+        self.queue.submit(Some(encoder.finish()));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| sender.send(result).unwrap());
+        self.device
+            .poll(wgpu::PollType::Wait)
+            .context("failed to wait for the GPU")?;
+        receiver.recv()??;
+        Ok(())
+    }
+
+    /// Traces the samples of the next frame, see `set_sample_budget`, and draws the average of all
+    /// samples to `target`.
     pub fn render_frame(&mut self, target: &wgpu::TextureView,camera:&Camera) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("render frame"),
+            });
+        self.encode_trace(&mut encoder, camera);
+        self.encode_display(&mut encoder, target);
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    /// Traces the samples of the next frame without displaying the result, see `read_radiance`.
+    pub fn trace(&mut self, camera: &Camera) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("trace"),
+            });
+        self.encode_trace(&mut encoder, camera);
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn encode_trace(&mut self, encoder: &mut wgpu::CommandEncoder, camera: &Camera) {
+        // The path statistics start over with the radiance sums.
+        if self.uniforms.frame_count == 0 {
+            encoder.clear_buffer(&self.path_statistics, 0, None);
+        }
+        self.uniforms.samples_per_frame = self.next_samples_per_frame();
         self.uniforms.frame_count += 1;
+        self.uniforms.sample_count += self.uniforms.samples_per_frame;
         self.uniforms.camera = *camera.uniforms();
 
             self.queue.write_buffer(
@@ -161,14 +864,24 @@ impl PathTracer {
             0,
             bytemuck::bytes_of(&self.uniforms),
         );
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("render frame"),
-            });
 
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("path tracer compute pass"),
+            timestamp_writes: None,
+        });
+        compute_pass.set_pipeline(&self.trace_pipeline);
+        let bind_group = &self.trace_bind_groups[(self.uniforms.frame_count % 2) as usize];
+        compute_pass.set_bind_group(0, bind_group, &[]);
+        compute_pass.dispatch_workgroups(
+            self.uniforms.width.div_ceil(WORKGROUP_SIZE),
+            self.uniforms.height.div_ceil(WORKGROUP_SIZE),
+            1,
+        );
+    }
+
+    fn encode_display(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("path tracer render pass"),
+            label: Some("display render pass"),
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                 view: target,
                 depth_slice: None,
@@ -181,20 +894,14 @@ impl PathTracer {
             ..Default::default()
         });
 
-        render_pass.set_pipeline(&self.pipeline);
-        render_pass.set_bind_group(0, &self.bind_groups[(self.uniforms.frame_count % 2) as usize], &[]);
+        // The last trace wrote its sum to the texture that the next one reads from.
+        let latest = (self.uniforms.frame_count as usize + 1) % 2;
+        render_pass.set_pipeline(&self.display_pipeline);
+        render_pass.set_bind_group(0, &self.display_bind_groups[latest], &[]);
 
-        // Draw 1 instance of a polygon with 3 vertices.
+        // Draw the two triangles of a full-screen quad.
         render_pass.draw(0..6, 0..1);
-
-        // End the render pass by consuming the object.
-        drop(render_pass);
-
-        let command_buffer = encoder.finish();
-        self.queue.submit(Some(command_buffer));
     }
-
-
 }
 fn compile_shader_module(device: &wgpu::Device) -> wgpu::ShaderModule {
     use std::borrow::Cow;
@@ -207,11 +914,17 @@ fn compile_shader_module(device: &wgpu::Device) -> wgpu::ShaderModule {
 }
 
 
-fn create_bind_groups(texture: [wgpu::Texture; 2], device: &wgpu::Device, layout: &wgpu::BindGroupLayout, uniform_buffer: &wgpu::Buffer) -> [wgpu::BindGroup; 2] {
+fn create_trace_bind_groups(texture: &[wgpu::Texture; 2], device: &wgpu::Device, layout: &wgpu::BindGroupLayout, uniform_buffer: &wgpu::Buffer, scene_buffers: &SceneBuffers, path_statistics: &wgpu::Buffer) -> [wgpu::BindGroup; 2] {
         let view = [
             texture[0].create_view(&wgpu::TextureViewDescriptor::default()),
             texture[1].create_view(&wgpu::TextureViewDescriptor::default()),
         ];
+        let environment_map =
+            scene_buffers.environment_map.create_view(&wgpu::TextureViewDescriptor::default());
+        let environment_cdf =
+            scene_buffers.environment_cdf.create_view(&wgpu::TextureViewDescriptor::default());
+        let blue_noise =
+            scene_buffers.blue_noise.create_view(&wgpu::TextureViewDescriptor::default());
         
         [
             device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -234,6 +947,50 @@ fn create_bind_groups(texture: [wgpu::Texture; 2], device: &wgpu::Device, layout
                     binding: 2,
                     resource: wgpu::BindingResource::TextureView(&view[1]),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: scene_buffers.spheres.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: scene_buffers.materials.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: scene_buffers.vertices.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: scene_buffers.triangles.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: scene_buffers.bvh_nodes.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: scene_buffers.bvh_primitives.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: scene_buffers.lights.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::TextureView(&environment_map),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: wgpu::BindingResource::TextureView(&environment_cdf),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: path_statistics.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 13,
+                    resource: wgpu::BindingResource::TextureView(&blue_noise),
+                },
             ],
         }),
         device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -256,8 +1013,77 @@ fn create_bind_groups(texture: [wgpu::Texture; 2], device: &wgpu::Device, layout
                     binding: 2,
                     resource: wgpu::BindingResource::TextureView(&view[0]),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: scene_buffers.spheres.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: scene_buffers.materials.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: scene_buffers.vertices.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: scene_buffers.triangles.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: scene_buffers.bvh_nodes.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: scene_buffers.bvh_primitives.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: scene_buffers.lights.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::TextureView(&environment_map),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: wgpu::BindingResource::TextureView(&environment_cdf),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: path_statistics.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 13,
+                    resource: wgpu::BindingResource::TextureView(&blue_noise),
+                },
             ],
         })
         ]
 
 }
+
+fn create_display_bind_groups(
+    textures: &[wgpu::Texture; 2],
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    uniform_buffer: &wgpu::Buffer,
+) -> [wgpu::BindGroup; 2] {
+    textures.each_ref().map(|texture| {
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("display"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: RADIANCE_SUM_BINDING,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+            ],
+        })
+    })
+}
@@ -0,0 +1,314 @@
+//! The sample generators that the path tracer draws its random numbers from, and the CPU versions
+//! of them in shaders.wgsl that validate.rs compares against each other.
+
+use {
+    crate::sampling::{jenkins_hash, Rng},
+    anyhow::{bail, Error},
+    std::{fmt, str::FromStr},
+};
+
+/// How the numbers of the samples of a pixel are generated. The values are those of the
+/// `SAMPLER_*` constants in shaders.wgsl.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Sampler {
+    /// Independent numbers from xorshift32, seeded by the pixel and the index of the sample.
+    Random = 0,
+    /// The Sobol sequence with nested uniform (Owen) scrambling, four dimensions at a time with
+    /// independently shuffled and scrambled sets of four, after Brent Burley, "Practical Hash-based
+    /// Owen Scrambling", JCGT 2020.
+    Sobol = 1,
+    /// Pairs of dimensions from the R2 sequence, a rank-1 lattice, rotated by a blue noise texture
+    /// so that the errors of neighbouring pixels differ. See Martin Roberts, "The Unreasonable
+    /// Effectiveness of Quasirandom Sequences", 2018, and Iliyan Georgiev and Marcos Fajardo,
+    /// "Blue-noise Dithered Sampling", 2016.
+    BlueNoise = 2,
+}
+
+const ALL: [Sampler; 3] = [Sampler::Random, Sampler::Sobol, Sampler::BlueNoise];
+
+/// Width and height of the blue noise texture, which is tiled over the image.
+pub const BLUE_NOISE_SIZE: u32 = 64;
+// Bits of the rank of a texel of the blue noise texture, with `BLUE_NOISE_SIZE` squared ranks.
+const BLUE_NOISE_RANK_BITS: u32 = 2 * BLUE_NOISE_SIZE.trailing_zeros();
+// Standard deviation in texels of the Gaussian filter that measures how crowded a texel is when
+// the blue noise texture is generated, as in Ulichney's paper, and the radius beyond which its
+// weights are negligible.
+const VOID_AND_CLUSTER_SIGMA: f32 = 1.5;
+const VOID_AND_CLUSTER_RADIUS: i32 = 6;
+
+// The first four dimensions of the Sobol sequence: van der Corput, then those of the primitive
+// polynomials x + 1, x^2 + x + 1 and x^3 + x + 1. Same as `SOBOL_DIRECTIONS` in shaders.wgsl.
+const SOBOL_DIRECTIONS: [[u32; 32]; 4] = [
+    [
+        0x80000000, 0x40000000, 0x20000000, 0x10000000, 0x08000000, 0x04000000, 0x02000000,
+        0x01000000, 0x00800000, 0x00400000, 0x00200000, 0x00100000, 0x00080000, 0x00040000,
+        0x00020000, 0x00010000, 0x00008000, 0x00004000, 0x00002000, 0x00001000, 0x00000800,
+        0x00000400, 0x00000200, 0x00000100, 0x00000080, 0x00000040, 0x00000020, 0x00000010,
+        0x00000008, 0x00000004, 0x00000002, 0x00000001,
+    ],
+    [
+        0x80000000, 0xc0000000, 0xa0000000, 0xf0000000, 0x88000000, 0xcc000000, 0xaa000000,
+        0xff000000, 0x80800000, 0xc0c00000, 0xa0a00000, 0xf0f00000, 0x88880000, 0xcccc0000,
+        0xaaaa0000, 0xffff0000, 0x80008000, 0xc000c000, 0xa000a000, 0xf000f000, 0x88008800,
+        0xcc00cc00, 0xaa00aa00, 0xff00ff00, 0x80808080, 0xc0c0c0c0, 0xa0a0a0a0, 0xf0f0f0f0,
+        0x88888888, 0xcccccccc, 0xaaaaaaaa, 0xffffffff,
+    ],
+    [
+        0x80000000, 0xc0000000, 0x60000000, 0x90000000, 0xe8000000, 0x5c000000, 0x8e000000,
+        0xc5000000, 0x68800000, 0x9cc00000, 0xee600000, 0x55900000, 0x80680000, 0xc09c0000,
+        0x60ee0000, 0x90550000, 0xe8808000, 0x5cc0c000, 0x8e606000, 0xc5909000, 0x6868e800,
+        0x9c9c5c00, 0xeeee8e00, 0x5555c500, 0x8000e880, 0xc0005cc0, 0x60008e60, 0x9000c590,
+        0xe8006868, 0x5c009c9c, 0x8e00eeee, 0xc5005555,
+    ],
+    [
+        0x80000000, 0xc0000000, 0x20000000, 0x50000000, 0xf8000000, 0x74000000, 0xa2000000,
+        0x93000000, 0xd8800000, 0x25400000, 0x59e00000, 0xe6d00000, 0x78080000, 0xb40c0000,
+        0x82020000, 0xc3050000, 0x208f8000, 0x51474000, 0xfbea2000, 0x75d93000, 0xa0858800,
+        0x914e5400, 0xdbe79e00, 0x25db6d00, 0x58800080, 0xe54000c0, 0x79e00020, 0xb6d00050,
+        0x800800f8, 0xc00c0074, 0x200200a2, 0x50050093,
+    ],
+];
+
+// The R2 sequence in 32-bit fixed point, 2^32 divided by the plastic number and by its square.
+// Same as `R2_GENERATORS` in shaders.wgsl.
+const R2_GENERATORS: [u32; 2] = [0xc13fa9a9, 0x91e10da6];
+
+impl Sampler {
+    /// The sampler after this one, wrapping around to the first.
+    pub fn next(self) -> Sampler {
+        ALL[(self as usize + 1) % ALL.len()]
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Sampler::Random => "random",
+            Sampler::Sobol => "sobol",
+            Sampler::BlueNoise => "blue-noise",
+        }
+    }
+}
+
+impl fmt::Display for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Sampler {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Sampler, Error> {
+        match ALL.into_iter().find(|sampler| sampler.name() == s) {
+            Some(sampler) => Ok(sampler),
+            None => {
+                let names: Vec<_> = ALL.iter().map(|sampler| sampler.name()).collect();
+                bail!("unknown sampler {}, expected one of {}", s, names.join(", "))
+            }
+        }
+    }
+}
+
+/// CPU version of `init_rng` and `rand_f32` in shaders.wgsl: the numbers of one sample of a pixel,
+/// in the order that the dimensions of the path are drawn.
+pub struct PixelSampler<'a> {
+    sampler: Sampler,
+    pixel: [u32; 2],
+    // Scalar index of the pixel, which seeds the scrambling of the Sobol sequence.
+    pixel_index: u32,
+    index: u32,
+    dimension: u32,
+    rng: Rng,
+    blue_noise: &'a [[u32; 2]],
+}
+
+impl<'a> PixelSampler<'a> {
+    /// The sample `index` of `pixel` in an image `width` pixels wide. `blue_noise` is the texture
+    /// made by `blue_noise_texture`.
+    pub fn new(
+        sampler: Sampler,
+        pixel: [u32; 2],
+        width: u32,
+        index: u32,
+        blue_noise: &'a [[u32; 2]],
+    ) -> PixelSampler<'a> {
+        let pixel_index = pixel[0] + pixel[1] * width;
+        PixelSampler {
+            sampler,
+            pixel,
+            pixel_index,
+            index,
+            dimension: 0,
+            rng: Rng::new(pixel_index ^ jenkins_hash(index)),
+            blue_noise,
+        }
+    }
+
+    /// Returns the next dimension of the sample, a number in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        let bits = match self.sampler {
+            Sampler::Sobol => sobol_sample(self.pixel_index, self.index, self.dimension),
+            Sampler::BlueNoise => {
+                blue_noise_sample(self.blue_noise, self.pixel, self.index, self.dimension)
+            }
+            Sampler::Random => self.rng.next_u32(),
+        };
+        self.dimension += 1;
+        f32::from_bits(0x3f800000 | (bits >> 9)) - 1.
+    }
+}
+
+// Same as `sobol_sample` in shaders.wgsl.
+fn sobol_sample(pixel_index: u32, index: u32, dimension: u32) -> u32 {
+    let seed = hash_combine(pixel_index, dimension / 4);
+    let index = nested_uniform_scramble(index, seed);
+    let component = dimension % 4;
+    let mut x = 0;
+    for (bit, direction) in SOBOL_DIRECTIONS[component as usize].iter().enumerate() {
+        if (index >> bit) & 1 != 0 {
+            x ^= direction;
+        }
+    }
+    nested_uniform_scramble(x, hash_combine(seed, component))
+}
+
+// Same as `blue_noise_sample` in shaders.wgsl.
+fn blue_noise_sample(texture: &[[u32; 2]], pixel: [u32; 2], index: u32, dimension: u32) -> u32 {
+    // Each pair of dimensions is a point of the R2 sequence, rotated by the two channels of the
+    // texture. The texture is shifted and the points are shuffled differently for each pair so
+    // that the pairs are not correlated. Like the Sobol sequence, the first 2^k points of the
+    // shuffled sequence are a run of 2^k consecutive points, which are as evenly spread.
+    let seed = jenkins_hash(dimension / 2);
+    let x = pixel[0].wrapping_add(seed) % BLUE_NOISE_SIZE;
+    let y = pixel[1].wrapping_add(seed >> 6) % BLUE_NOISE_SIZE;
+    let component = (dimension % 2) as usize;
+    let rotation = texture[(y * BLUE_NOISE_SIZE + x) as usize][component];
+    let index = nested_uniform_scramble(index, seed);
+    rotation.wrapping_add(index.wrapping_mul(R2_GENERATORS[component]))
+}
+
+// Same as `hash_combine` in shaders.wgsl.
+fn hash_combine(seed: u32, value: u32) -> u32 {
+    jenkins_hash(seed ^ jenkins_hash(value))
+}
+
+// Same as `nested_uniform_scramble` in shaders.wgsl: an Owen scrambling of the bits of `x`, where
+// each bit is flipped depending on the bits above it.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// A hash in which each bit only depends on the bits below it, from Burley's paper.
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// The blue noise texture of `BLUE_NOISE_SIZE` squared texels in row major order, with two
+/// independent channels. Each channel holds every rank of a void-and-cluster ordering once, as the
+/// centre of its interval of [0, 1) in 32-bit fixed point.
+pub fn blue_noise_texture() -> Vec<[u32; 2]> {
+    let channels = [1, 2].map(void_and_cluster);
+    let shift = 32 - BLUE_NOISE_RANK_BITS;
+    (0..channels[0].len())
+        .map(|i| channels.each_ref().map(|ranks| (ranks[i] << shift) | (1 << (shift - 1))))
+        .collect()
+}
+
+// Ranks the texels of a tile of `BLUE_NOISE_SIZE` squared texels so that those below any rank are
+// spread out evenly, also across the edges of the tile. See Robert Ulichney, "The void-and-cluster
+// method for dither array generation", 1993.
+fn void_and_cluster(seed: u32) -> Vec<u32> {
+    let texels = (BLUE_NOISE_SIZE * BLUE_NOISE_SIZE) as usize;
+    let mut rng = Rng::new(seed);
+    let mut pattern = Pattern::new(texels);
+    while pattern.count < texels / 10 {
+        let i = rng.next_u32() as usize % texels;
+        if !pattern.points[i] {
+            pattern.toggle(i);
+        }
+    }
+    // Even out the random points by moving the one in the tightest cluster to the largest void,
+    // until that is where it was. This takes far fewer moves than the limit.
+    for _ in 0..texels {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // The points of the initial pattern are ranked by taking them out of the tightest cluster
+    // first, and the other texels by filling the largest void first.
+    let mut ranks = vec![0; texels];
+    let mut removed = pattern.clone();
+    for rank in (0..pattern.count).rev() {
+        let cluster = removed.tightest_cluster();
+        removed.toggle(cluster);
+        ranks[cluster] = rank as u32;
+    }
+    for rank in pattern.count..texels {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        ranks[void] = rank as u32;
+    }
+    ranks
+}
+
+// A binary pattern of points on a tile, with how crowded each texel is by the points around it.
+#[derive(Clone)]
+struct Pattern {
+    points: Vec<bool>,
+    count: usize,
+    // The points filtered with a Gaussian that wraps around the edges of the tile.
+    density: Vec<f32>,
+}
+
+impl Pattern {
+    fn new(texels: usize) -> Pattern {
+        Pattern { points: vec![false; texels], count: 0, density: vec![0.; texels] }
+    }
+
+    // Adds a point at texel `i` or removes the one there.
+    fn toggle(&mut self, i: usize) {
+        let sign = if self.points[i] { -1. } else { 1. };
+        self.points[i] = !self.points[i];
+        self.count = if self.points[i] { self.count + 1 } else { self.count - 1 };
+        let size = BLUE_NOISE_SIZE as i32;
+        let (x, y) = (i as i32 % size, i as i32 / size);
+        let r = VOID_AND_CLUSTER_RADIUS;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let weight = (-((dx * dx + dy * dy) as f32)
+                    / (2. * VOID_AND_CLUSTER_SIGMA * VOID_AND_CLUSTER_SIGMA))
+                    .exp();
+                let j = (y + dy).rem_euclid(size) * size + (x + dx).rem_euclid(size);
+                self.density[j as usize] += sign * weight;
+            }
+        }
+    }
+
+    // The point with the most other points close to it.
+    fn tightest_cluster(&self) -> usize {
+        self.most_crowded(true, 1.)
+    }
+
+    // The texel without a point that is farthest from the points around it.
+    fn largest_void(&self) -> usize {
+        self.most_crowded(false, -1.)
+    }
+
+    // The texel whose density times `sign` is largest among those with or without a point.
+    fn most_crowded(&self, point: bool, sign: f32) -> usize {
+        (0..self.points.len())
+            .filter(|&i| self.points[i] == point)
+            .max_by(|&a, &b| (sign * self.density[a]).total_cmp(&(sign * self.density[b])))
+            .expect("the pattern has no texel to choose from")
+    }
+}
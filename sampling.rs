@@ -0,0 +1,89 @@
+use {crate::math::Vec4, std::f32::consts::PI};
+
+/// CPU version of the random number generator in shaders.wgsl, used to generate test data and to
+/// reproduce the sampling routines of the shader.
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Rng {
+        // A zero state would make xorshift32 return zeros forever.
+        Rng { state: jenkins_hash(seed).max(1) }
+    }
+
+    // The 32-bit "xor" function from Marsaglia G., "Xorshift RNGs", Section 3.
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Returns a uniformly distributed number in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        f32::from_bits(0x3f800000 | (self.next_u32() >> 9)) - 1.
+    }
+
+    /// Returns a uniformly distributed number in [lo, hi).
+    pub fn range(&mut self, lo: f32, hi: f32) -> f32 {
+        lo + (hi - lo) * self.next_f32()
+    }
+}
+
+/// A slightly modified version of the "One-at-a-Time Hash" function by Bob Jenkins, same as
+/// `jenkins_hash` in shaders.wgsl.
+/// See https://www.burtleburtle.net/bob/hash/doobs.html
+pub fn jenkins_hash(i: u32) -> u32 {
+    let mut x = i;
+    x = x.wrapping_add(x << 10);
+    x ^= x >> 6;
+    x = x.wrapping_add(x << 3);
+    x ^= x >> 11;
+    x = x.wrapping_add(x << 15);
+    x
+}
+
+/// Returns a uniformly distributed direction, same as `sample_sphere` in shaders.wgsl.
+pub fn sample_sphere(rng: &mut Rng) -> Vec4 {
+    let y = 1. - 2. * rng.next_f32();
+    let xz_r = (1. - y * y).max(0.).sqrt();
+    let phi = 2. * PI * rng.next_f32();
+    Vec4::new(xz_r * phi.cos(), y, xz_r * phi.sin())
+}
+
+/// Returns a direction in the hemisphere around +z, distributed proportionally to its cosine with
+/// z. Same as `sample_cosine_hemisphere` in shaders.wgsl.
+pub fn sample_cosine_hemisphere(rng: &mut Rng) -> Vec4 {
+    let r = rng.next_f32().sqrt();
+    let phi = 2. * PI * rng.next_f32();
+    Vec4::new(r * phi.cos(), r * phi.sin(), (1. - r * r).max(0.).sqrt())
+}
+
+/// An orthonormal basis whose third axis is a given unit vector, same as `orthonormal_basis` in
+/// shaders.wgsl.
+pub struct Frame {
+    tangent: Vec4,
+    bitangent: Vec4,
+    normal: Vec4,
+}
+
+impl Frame {
+    pub fn new(n: Vec4) -> Frame {
+        let s = if n.z() >= 0. { 1. } else { -1. };
+        let a = -1. / (s + n.z());
+        let b = n.x() * n.y() * a;
+        Frame {
+            tangent: Vec4::new(1. + s * n.x() * n.x() * a, s * b, -s * n.x()),
+            bitangent: Vec4::new(b, s + n.y() * n.y() * a, -n.y()),
+            normal: n,
+        }
+    }
+
+    /// Transforms a direction from the local frame, where the normal is +z, to world space.
+    pub fn to_world(&self, v: Vec4) -> Vec4 {
+        self.tangent * v.x() + self.bitangent * v.y() + self.normal * v.z()
+    }
+}
@@ -0,0 +1,714 @@
+use {
+    crate::{
+        bvh::{Aabb, Bvh},
+        camera::{self, Camera, Projection},
+        environment::EnvironmentMap,
+        math::{Ray, Vec4},
+        mesh,
+        sky::Sky,
+    },
+    anyhow::{anyhow, bail, Context, Result},
+    bytemuck::{Pod, Zeroable},
+    serde::Deserialize,
+    std::{collections::HashMap, path::Path},
+};
+
+// Material type tags. These must match the `MATERIAL_*` constants in shaders.wgsl.
+const MATERIAL_METAL: u32 = 0;
+const MATERIAL_LAMBERTIAN: u32 = 1;
+const MATERIAL_GLASS: u32 = 2;
+const MATERIAL_EMISSIVE: u32 = 3;
+const MATERIAL_PRINCIPLED: u32 = 4;
+
+// Primitive ids stored in the BVH are indices into the sphere array, or into the triangle array
+// when this bit is set. This must match `PRIMITIVE_TRIANGLE_BIT` in shaders.wgsl.
+const PRIMITIVE_TRIANGLE_BIT: u32 = 1 << 31;
+
+/// GPU representation of a sphere, laid out to match `struct Sphere` in shaders.wgsl.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Sphere {
+    center: [f32; 3],
+    radius: f32,
+    material: u32,
+    _padding: [u32; 3],
+}
+
+/// GPU representation of a material, laid out to match `struct Material` in shaders.wgsl.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Material {
+    colour: [f32; 3],
+    type_mat: u32,
+    // Emitted radiance, i.e. the emission colour scaled by the emission strength.
+    emission: [f32; 3],
+    refractive_index: f32,
+    // Absorption coefficient of the interior of a dielectric.
+    absorption: [f32; 3],
+    // GGX roughness of metals and principled materials, between 0 (a perfect mirror) and 1.
+    roughness: f32,
+    // Parameters of principled materials, which also use `colour` as their base colour,
+    // `refractive_index` for transmission and `roughness`. See `MaterialKind::Principled`.
+    metallic: f32,
+    specular: f32,
+    clearcoat: f32,
+    transmission: f32,
+}
+
+/// GPU representation of a mesh vertex, laid out to match `struct Vertex` in shaders.wgsl.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Vertex {
+    position: [f32; 3],
+    _padding0: u32,
+    normal: [f32; 3],
+    _padding1: u32,
+}
+
+/// GPU representation of a triangle, laid out to match `struct Triangle` in shaders.wgsl.
+/// `indices` refer to the vertex buffer shared by all meshes of the scene.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Triangle {
+    indices: [u32; 3],
+    material: u32,
+}
+
+/// GPU representation of an emissive primitive, laid out to match `struct Light` in shaders.wgsl.
+/// Lights are picked with probability proportional to their power, `cdf` is the summed power of
+/// this light and all lights before it.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct Light {
+    primitive: u32,
+    cdf: f32,
+}
+
+impl Sphere {
+    pub fn new(center: Vec4, radius: f32, material: u32) -> Sphere {
+        Sphere {
+            center: center.to_array(),
+            radius,
+            material,
+            _padding: [0; 3],
+        }
+    }
+
+    fn area(&self) -> f32 {
+        4. * std::f32::consts::PI * self.radius * self.radius
+    }
+
+    fn aabb(&self) -> Aabb {
+        let center = Vec4::from(self.center);
+        Aabb {
+            min: center - Vec4::all(self.radius),
+            max: center + Vec4::all(self.radius),
+        }
+    }
+
+    // Same as `intersect_sphere` in shaders.wgsl.
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let v = ray.origin - Vec4::from(self.center);
+        let a = ray.direction.dot(&ray.direction);
+        let b = v.dot(&ray.direction);
+        let c = v.dot(&v) - self.radius * self.radius;
+        let d = b * b - a * c;
+        if d < 0. {
+            return None;
+        }
+        let sqrt_d = d.sqrt();
+        let t1 = (-b - sqrt_d) / a;
+        let t2 = (-b + sqrt_d) / a;
+        let t = if t1 > 0. { t1 } else { t2 };
+        (t > 0.).then_some(t)
+    }
+}
+
+impl Vertex {
+    pub fn new(position: Vec4, normal: Vec4) -> Vertex {
+        Vertex {
+            position: position.to_array(),
+            _padding0: 0,
+            normal: normal.to_array(),
+            _padding1: 0,
+        }
+    }
+}
+
+impl Triangle {
+    pub fn new(indices: [u32; 3], material: u32) -> Triangle {
+        Triangle { indices, material }
+    }
+}
+
+/// A scene that is ready to be uploaded to the GPU.
+pub struct Scene {
+    pub spheres: Vec<Sphere>,
+    pub materials: Vec<Material>,
+    pub vertices: Vec<Vertex>,
+    pub triangles: Vec<Triangle>,
+    pub bvh: Bvh,
+    pub lights: Vec<Light>,
+    pub camera: Camera,
+    pub environment: Option<Environment>,
+    // A physical sky replacing the default one. Scenes do not have both this and an environment.
+    pub sky: Option<Sky>,
+}
+
+/// An environment map replacing the default sky, with its initial orientation and brightness.
+pub struct Environment {
+    pub map: EnvironmentMap,
+    // Rotation around the y axis in degrees, counterclockwise seen from above.
+    pub rotation: f32,
+    // Factor that the radiance of the map is scaled by.
+    pub intensity: f32,
+}
+
+// The scene description format. Scene files are written in RON, for example:
+//
+//     (
+//         materials: [
+//             (colour: (0.5, 0.5, 0.5), kind: Metal(roughness: 0.2)),
+//             (colour: (0.9, 0.5, 0.3), kind: Glass(refractive_index: 1.5)),
+//         ],
+//         spheres: [
+//             (center: (0.0, 0.0, -1.0), radius: 0.5, material: 1),
+//         ],
+//         quads: [
+//             (corner: (-1.0, 2.0, -1.0), u: (2.0, 0.0, 0.0), v: (0.0, 0.0, 2.0), material: 0),
+//         ],
+//         meshes: [
+//             (path: "models/teapot.obj", material: 0, groups: { "lid": 1 }),
+//         ],
+//         camera: (origin: (0.0, 1.0, 3.0), look_at: (0.0, 1.0, 0.0), aperture_radius: 0.05),
+//         environment: (path: "studio.hdr", rotation: 90.0, intensity: 2.0),
+//     )
+//
+// Outdoor scenes can use a physical sky instead of an environment map, for example
+// `sky: (turbidity: 3.0, sun_elevation: 30.0, sun_azimuth: 120.0)`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    materials: Vec<MaterialDesc>,
+    #[serde(default)]
+    spheres: Vec<SphereDesc>,
+    #[serde(default)]
+    quads: Vec<QuadDesc>,
+    #[serde(default)]
+    meshes: Vec<MeshDesc>,
+    camera: Option<CameraDesc>,
+    environment: Option<EnvironmentDesc>,
+    sky: Option<SkyDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SkyDesc {
+    // Haziness of the air, from 2 for a very clear sky to 10 for a hazy one.
+    #[serde(default = "default_turbidity")]
+    turbidity: f32,
+    // Position of the sun in degrees, above the horizon and east of north (-z).
+    #[serde(default = "default_sun_elevation")]
+    sun_elevation: f32,
+    #[serde(default)]
+    sun_azimuth: f32,
+    // Latitude in degrees north, which sets the arc that the sun follows through the day.
+    #[serde(default = "default_latitude")]
+    latitude: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnvironmentDesc {
+    // An equirectangular .hdr or .exr image, relative to the scene file.
+    path: String,
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    origin: [f32; 3],
+    look_at: [f32; 3],
+    #[serde(default = "default_up")]
+    up: [f32; 3],
+    // Thin lens parameters, see `Camera`. The focus is at `look_at` unless given.
+    #[serde(default)]
+    aperture_radius: f32,
+    focus_distance: Option<f32>,
+    #[serde(default)]
+    aperture_blades: u32,
+    // Vertical field of view in degrees.
+    #[serde(default = "default_vfov")]
+    vfov: f32,
+    #[serde(default = "default_projection")]
+    projection: Projection,
+}
+
+impl CameraDesc {
+    fn build(&self) -> Result<Camera> {
+        if self.aperture_radius < 0. {
+            bail!("the camera aperture radius must not be negative");
+        }
+        if self.up == [0.; 3] {
+            bail!("the camera up direction must not be zero");
+        }
+        if !(camera::MIN_VFOV..=camera::MAX_VFOV).contains(&self.vfov) {
+            bail!(
+                "the camera field of view must be between {} and {} degrees",
+                camera::MIN_VFOV,
+                camera::MAX_VFOV
+            );
+        }
+        let planar = matches!(self.projection, Projection::Perspective | Projection::Orthographic);
+        if planar && self.vfov >= 180. {
+            bail!("perspective and orthographic cameras need a field of view below 180 degrees");
+        }
+        let mut camera = Camera::look_at(self.origin.into(), self.look_at.into(), self.up.into());
+        camera.set_aperture_radius(self.aperture_radius);
+        camera.set_aperture_blades(self.aperture_blades);
+        camera.set_vfov(self.vfov);
+        camera.set_projection(self.projection);
+        if let Some(distance) = self.focus_distance {
+            if distance <= 0. {
+                bail!("the camera focus distance must be positive");
+            }
+            camera.set_focus_distance(distance);
+        }
+        Ok(camera)
+    }
+}
+
+fn default_vfov() -> f32 {
+    camera::DEFAULT_VFOV
+}
+
+fn default_projection() -> Projection {
+    Projection::Perspective
+}
+
+fn default_intensity() -> f32 {
+    1.
+}
+
+fn default_turbidity() -> f32 {
+    3.
+}
+
+fn default_sun_elevation() -> f32 {
+    45.
+}
+
+fn default_latitude() -> f32 {
+    40.
+}
+
+fn default_up() -> [f32; 3] {
+    [0., 1., 0.]
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SphereDesc {
+    center: [f32; 3],
+    radius: f32,
+    material: usize,
+}
+
+// A parallelogram spanned by the edges `u` and `v` starting at `corner`. Quads are split into two
+// triangles.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuadDesc {
+    corner: [f32; 3],
+    u: [f32; 3],
+    v: [f32; 3],
+    material: usize,
+}
+
+// A Wavefront OBJ file placed in the scene. Mesh paths are relative to the scene file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDesc {
+    path: String,
+    // The material of all groups that are not listed in `groups`.
+    material: usize,
+    // Per-group material overrides, keyed by the OBJ object or group name.
+    #[serde(default)]
+    groups: HashMap<String, usize>,
+    #[serde(default)]
+    translation: [f32; 3],
+    #[serde(default = "default_scale")]
+    scale: f32,
+}
+
+fn default_scale() -> f32 {
+    1.
+}
+
+fn default_absorption_distance() -> f32 {
+    1.
+}
+
+fn default_principled_roughness() -> f32 {
+    0.5
+}
+
+fn default_principled_specular() -> f32 {
+    0.5
+}
+
+fn default_principled_ior() -> f32 {
+    1.5
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    colour: [f32; 3],
+    kind: MaterialKind,
+}
+
+#[derive(Deserialize)]
+enum MaterialKind {
+    Lambertian,
+    // A conductor with GGX microfacets. A roughness of 0 is a perfect mirror.
+    Metal {
+        #[serde(default)]
+        roughness: f32,
+    },
+    // A dielectric. Light travelling `absorption_distance` inside it is filtered down to `colour`,
+    // so thicker parts of an object appear darker.
+    Glass {
+        refractive_index: f32,
+        #[serde(default = "default_absorption_distance")]
+        absorption_distance: f32,
+    },
+    // A light source that emits `colour` scaled by `strength` and does not scatter light.
+    Emissive { strength: f32 },
+    // A single material model covering plastics, metals, coated paints and glass, after Burley,
+    // "Physically Based Shading at Disney". It blends a diffuse base, a GGX specular layer whose
+    // reflectance at normal incidence is 0.08 * `specular` for dielectrics and `colour` for
+    // metals, a rough dielectric transmitting `colour`, and a clear coat on top.
+    Principled {
+        #[serde(default)]
+        metallic: f32,
+        #[serde(default = "default_principled_roughness")]
+        roughness: f32,
+        #[serde(default = "default_principled_specular")]
+        specular: f32,
+        #[serde(default)]
+        clearcoat: f32,
+        #[serde(default)]
+        transmission: f32,
+        #[serde(default = "default_principled_ior")]
+        ior: f32,
+    },
+}
+
+impl Scene {
+    /// Creates a scene and builds the BVH over all of its primitives.
+    pub fn new(
+        spheres: Vec<Sphere>,
+        materials: Vec<Material>,
+        vertices: Vec<Vertex>,
+        triangles: Vec<Triangle>,
+    ) -> Scene {
+        let sphere_bounds = spheres.iter().enumerate().map(|(i, s)| (i as u32, s.aabb()));
+        let triangle_bounds = triangles.iter().enumerate().map(|(i, t)| {
+            let positions = t.indices.map(|v| Vec4::from(vertices[v as usize].position));
+            (i as u32 | PRIMITIVE_TRIANGLE_BIT, Aabb::from_points(&positions))
+        });
+        let bvh = Bvh::build(sphere_bounds.chain(triangle_bounds));
+
+        // Collect the emissive primitives for light sampling. The power of a light is computed
+        // the same way as in `light_power` in shaders.wgsl.
+        let mut lights = vec![];
+        let mut total_power = 0.;
+        let emitters = spheres
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (i as u32, s.material, s.area()))
+            .chain(triangles.iter().enumerate().map(|(i, t)| {
+                let [p0, p1, p2] = t.indices.map(|v| Vec4::from(vertices[v as usize].position));
+                let area = 0.5 * (p1 - p0).cross(&(p2 - p0)).length();
+                (i as u32 | PRIMITIVE_TRIANGLE_BIT, t.material, area)
+            }));
+        for (primitive, material, area) in emitters {
+            let power = area * materials[material as usize].luminance();
+            if power > 0. {
+                total_power += power;
+                lights.push(Light { primitive, cdf: total_power });
+            }
+        }
+
+        let camera = Camera::look_at(
+            Vec4::new(0., 0.75, 1.),
+            Vec4::new(0., -0.5, -1.),
+            Vec4::new(0., 1., 0.),
+        );
+        Scene {
+            spheres,
+            materials,
+            vertices,
+            triangles,
+            bvh,
+            lights,
+            camera,
+            environment: None,
+            sky: None,
+        }
+    }
+
+    /// Loads and validates a scene description from a RON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Scene> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read scene file {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        Scene::parse(&source, &path.display().to_string(), dir)
+    }
+
+    /// The scene that is used when no scene file is given on the command line.
+    pub fn default_scene() -> Result<Scene> {
+        let source = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/src/scenes/default.ron"));
+        Scene::parse(source, "scenes/default.ron", Path::new("."))
+    }
+
+    /// Parses a scene description. `name` is only used to give errors some context and mesh and
+    /// environment map paths are resolved relative to `dir`.
+    pub fn parse(source: &str, name: &str, dir: &Path) -> Result<Scene> {
+        // Optional fields such as `camera` can be written without wrapping them in `Some(...)`.
+        let options = ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME);
+        let desc: SceneDesc = options
+            .from_str(source)
+            .map_err(|err| anyhow!("{}:{}: {}", name, err.position, err.code))?;
+        desc.build(dir).with_context(|| format!("invalid scene {}", name))
+    }
+
+    /// Returns the id of the closest primitive hit by `ray` and the distance to it.
+    pub fn intersect(&self, ray: &Ray) -> Option<(u32, f32)> {
+        self.bvh.closest_hit(ray, |id| self.intersect_primitive(id, ray))
+    }
+
+    /// Same as `intersect` but tests every primitive instead of traversing the BVH.
+    #[cfg(test)]
+    pub fn intersect_brute_force(&self, ray: &Ray) -> Option<(u32, f32)> {
+        let spheres = 0..self.spheres.len() as u32;
+        let triangles = (0..self.triangles.len() as u32).map(|i| i | PRIMITIVE_TRIANGLE_BIT);
+        spheres
+            .chain(triangles)
+            .filter_map(|id| self.intersect_primitive(id, ray).map(|t| (id, t)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    fn intersect_primitive(&self, id: u32, ray: &Ray) -> Option<f32> {
+        if id & PRIMITIVE_TRIANGLE_BIT == 0 {
+            return self.spheres[id as usize].intersect(ray);
+        }
+
+        // Moller-Trumbore, same as `intersect_triangle` in shaders.wgsl.
+        let triangle = &self.triangles[(id & !PRIMITIVE_TRIANGLE_BIT) as usize];
+        let [p0, p1, p2] = triangle.indices.map(|i| Vec4::from(self.vertices[i as usize].position));
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+        let p = ray.direction.cross(&e2);
+        let det = e1.dot(&p);
+        if det.abs() < 1e-8 {
+            return None;
+        }
+        let recip_det = 1. / det;
+        let s = ray.origin - p0;
+        let u = s.dot(&p) * recip_det;
+        if !(0. ..=1.).contains(&u) {
+            return None;
+        }
+        let q = s.cross(&e1);
+        let v = ray.direction.dot(&q) * recip_det;
+        if v < 0. || u + v > 1. {
+            return None;
+        }
+        let t = e2.dot(&q) * recip_det;
+        (t > 0.).then_some(t)
+    }
+}
+
+impl SceneDesc {
+    fn build(self, dir: &Path) -> Result<Scene> {
+        let check_material = |material: usize, what: &dyn Fn() -> String| {
+            if material >= self.materials.len() {
+                bail!(
+                    "{} refers to material {} but only {} materials are defined",
+                    what(),
+                    material,
+                    self.materials.len()
+                );
+            }
+            Ok(material as u32)
+        };
+
+        let materials = self
+            .materials
+            .iter()
+            .enumerate()
+            .map(|(i, material)| material.to_gpu().with_context(|| format!("material {}", i)))
+            .collect::<Result<_>>()?;
+        let spheres: Vec<Sphere> = self
+            .spheres
+            .iter()
+            .enumerate()
+            .map(|(i, sphere)| {
+                let material = check_material(sphere.material, &|| format!("sphere {}", i))?;
+                Ok(Sphere::new(sphere.center.into(), sphere.radius, material))
+            })
+            .collect::<Result<_>>()?;
+
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+        for (i, quad) in self.quads.iter().enumerate() {
+            let material = check_material(quad.material, &|| format!("quad {}", i))?;
+            let corner = Vec4::from(quad.corner);
+            let (u, v) = (Vec4::from(quad.u), Vec4::from(quad.v));
+            let normal = u.cross(&v).normalized();
+            let first_vertex = vertices.len() as u32;
+            for p in [corner, corner + u, corner + u + v, corner + v] {
+                vertices.push(Vertex::new(p, normal));
+            }
+            triangles.push(Triangle::new([0, 1, 2].map(|i| first_vertex + i), material));
+            triangles.push(Triangle::new([0, 2, 3].map(|i| first_vertex + i), material));
+        }
+        for desc in &self.meshes {
+            let default_material = check_material(desc.material, &|| format!("mesh {}", desc.path))?;
+            let groups = mesh::load_obj(&dir.join(&desc.path))?;
+            for name in desc.groups.keys() {
+                if !groups.iter().any(|group| &group.name == name) {
+                    bail!("mesh {} has no group named \"{}\"", desc.path, name);
+                }
+            }
+            let translation = Vec4::from(desc.translation);
+            for group in groups {
+                let material = match desc.groups.get(&group.name) {
+                    Some(&material) => check_material(material, &|| {
+                        format!("group \"{}\" of mesh {}", group.name, desc.path)
+                    })?,
+                    None => default_material,
+                };
+                let first_vertex = vertices.len() as u32;
+                vertices.extend(
+                    group.positions.iter().zip(&group.normals).map(|(p, n)| {
+                        Vertex::new(*p * desc.scale + translation, *n)
+                    }),
+                );
+                triangles.extend(
+                    group.triangles.iter().map(|t| Triangle::new(t.map(|i| first_vertex + i), material)),
+                );
+            }
+        }
+
+        if spheres.is_empty() && triangles.is_empty() {
+            bail!("the scene does not contain any spheres, quads or meshes");
+        }
+        let mut scene = Scene::new(spheres, materials, vertices, triangles);
+        if let Some(camera) = &self.camera {
+            scene.camera = camera.build()?;
+        }
+        if let Some(desc) = &self.environment {
+            if !(desc.intensity >= 0. && desc.intensity.is_finite()) {
+                bail!("the environment intensity must not be negative");
+            }
+            scene.environment = Some(Environment {
+                map: EnvironmentMap::load(&dir.join(&desc.path))?,
+                rotation: desc.rotation,
+                intensity: desc.intensity,
+            });
+        }
+        if let Some(desc) = &self.sky {
+            if self.environment.is_some() {
+                bail!("a scene cannot have both an environment map and a sky");
+            }
+            if !(2. ..=10.).contains(&desc.turbidity) {
+                bail!("the sky turbidity must be between 2 and 10");
+            }
+            let angle = -90. ..=90.;
+            if !angle.contains(&desc.sun_elevation) || !angle.contains(&desc.latitude) {
+                bail!("the sun elevation and the latitude must be between -90 and 90 degrees");
+            }
+            scene.sky =
+                Some(Sky::new(desc.turbidity, desc.sun_elevation, desc.sun_azimuth, desc.latitude));
+        }
+        Ok(scene)
+    }
+}
+
+impl Material {
+    // Luminance of the emitted radiance.
+    fn luminance(&self) -> f32 {
+        let [r, g, b] = self.emission;
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+}
+
+impl MaterialDesc {
+    fn to_gpu(&self) -> Result<Material> {
+        let mut material = Material {
+            colour: self.colour,
+            type_mat: 0,
+            emission: [0.; 3],
+            refractive_index: 0.,
+            absorption: [0.; 3],
+            roughness: 0.,
+            metallic: 0.,
+            specular: 0.,
+            clearcoat: 0.,
+            transmission: 0.,
+        };
+        match self.kind {
+            MaterialKind::Lambertian => material.type_mat = MATERIAL_LAMBERTIAN,
+            MaterialKind::Metal { roughness } => {
+                material.type_mat = MATERIAL_METAL;
+                material.roughness = roughness.clamp(0., 1.);
+            }
+            MaterialKind::Glass { refractive_index, absorption_distance } => {
+                if !(refractive_index.is_finite() && refractive_index > 0.) {
+                    bail!("the refractive index must be positive");
+                }
+                if absorption_distance.is_nan() || absorption_distance <= 0. {
+                    bail!("the absorption distance must be positive");
+                }
+                material.type_mat = MATERIAL_GLASS;
+                material.refractive_index = refractive_index;
+                // Beer-Lambert: the transmittance over distance d is exp(-absorption * d).
+                material.absorption =
+                    self.colour.map(|c| -c.clamp(1e-4, 1.).ln() / absorption_distance);
+            }
+            MaterialKind::Emissive { strength } => {
+                material.type_mat = MATERIAL_EMISSIVE;
+                material.emission = self.colour.map(|c| c * strength);
+            }
+            MaterialKind::Principled {
+                metallic,
+                roughness,
+                specular,
+                clearcoat,
+                transmission,
+                ior,
+            } => {
+                if !(ior.is_finite() && ior > 0.) {
+                    bail!("the index of refraction must be positive");
+                }
+                material.type_mat = MATERIAL_PRINCIPLED;
+                material.metallic = metallic.clamp(0., 1.);
+                material.roughness = roughness.clamp(0., 1.);
+                material.specular = specular.clamp(0., 1.);
+                material.clearcoat = clearcoat.clamp(0., 1.);
+                material.transmission = transmission.clamp(0., 1.);
+                material.refractive_index = ior;
+            }
+        }
+        Ok(material)
+    }
+}
@@ -1,9 +1,22 @@
 const FLT_MAX: f32 = 3.40282346638528859812e+38;
-const MAX_BOUNCES: u32 = 13u;
 const PI = 3.1415926;
 
+// Largest value of `uniforms.max_bounces`. Must match render.rs.
+const MAX_BOUNCE_LIMIT = 64u;
+// Number of counters in `path_statistics`: the number of paths that ended after each number of
+// bounces from 0 to `MAX_BOUNCE_LIMIT`, followed by the number of those that Russian roulette
+// terminated.
+const PATH_COUNTERS = 2u * (MAX_BOUNCE_LIMIT + 1u);
+
+// Whether the display output must be gamma encoded, which is the case unless the render target
+// has an sRGB or floating point format. Set by render.rs when the pipeline is created.
+override ENCODE_GAMMA: bool = true;
+
+// Width and height of the workgroups of `path_tracer_cs`. Must match render.rs.
+const WORKGROUP_SIZE = 8u;
+
 alias TriangleVertices = array<vec2f, 6>;
-var<private> vertices: TriangleVertices = TriangleVertices(
+var<private> quad_vertices: TriangleVertices = TriangleVertices(
   vec2f(-1.0,-1.0),
   vec2f(-1.0,1.0),
   vec2f(1.0, -1.0),
@@ -12,26 +25,95 @@ var<private> vertices: TriangleVertices = TriangleVertices(
   vec2f(1.0,1.0),
 );
 
-const OBJ_COUNT: u32 = 4u;
-alias Spheres = array<Sphere, 4u>;
-var<private> spheres: Spheres = Spheres(
-  Sphere( vec3(-1., 0., -1.), 0.5, 2),
-  Sphere(vec3(0., -100.5, 0.), 100., 1),
-  Sphere(vec3(1., 0., -1.), 0.5, 0),
-  Sphere(vec3(3., 0., -1.), 0.5, 1)
-);
+// Size of the stack used to traverse the BVH. The BVH builder in bvh.rs limits the depth of the
+// tree accordingly.
+const BVH_STACK_SIZE: u32 = 32u;
+
+// Primitive ids stored in the BVH are indices into `spheres`, or into `triangles` when this bit is
+// set. This must match the constant in scene.rs.
+const PRIMITIVE_TRIANGLE_BIT: u32 = 0x80000000u;
 
+// Material type tags. These must match the constants in scene.rs.
 const MATERIAL_LAMBERTIAN: u32 = 1u;
 const MATERIAL_METAL: u32 = 0u;
 const MATERIAL_GLASS: u32 = 2u;
-var<private> materials = array< Material,3>(
-Material( vec3(0.0, 0.8, 0.7), MATERIAL_LAMBERTIAN,0),
-Material( vec3(.5,0.5,.5), MATERIAL_METAL,0),
-Material( vec3(1.,1.,1.),MATERIAL_GLASS,2.),
+const MATERIAL_EMISSIVE: u32 = 3u;
+const MATERIAL_PRINCIPLED: u32 = 4u;
+
+// Smallest GGX alpha, which keeps the distribution finite in single precision.
+const MIN_GGX_ALPHA: f32 = 1e-3;
+// GGX alpha of the clear coat of principled materials.
+const CLEARCOAT_ALPHA: f32 = 0.05;
+
+// Camera projections, same as `Projection` in camera.rs.
+const PROJECTION_PERSPECTIVE: u32 = 0u;
+const PROJECTION_ORTHOGRAPHIC: u32 = 1u;
+const PROJECTION_EQUIRECTANGULAR: u32 = 2u;
+const PROJECTION_FISHEYE: u32 = 3u;
+
+// Largest half field of view of projections onto a plane, which cannot cover 180 degrees.
+const MAX_PLANAR_HALF_VFOV: f32 = 1.5621; // 89.5 degrees
+
+// Tone mapping operators, same as `ToneMapping` in tonemap.rs.
+const TONE_MAPPING_LINEAR: u32 = 0u;
+const TONE_MAPPING_REINHARD: u32 = 1u;
+const TONE_MAPPING_EXTENDED_REINHARD: u32 = 2u;
+const TONE_MAPPING_ACES: u32 = 3u;
+const TONE_MAPPING_AGX: u32 = 4u;
+
+// Luminance that extended Reinhard maps to white.
+const EXTENDED_REINHARD_WHITE: f32 = 4.;
+
+// Sample generators, same as `Sampler` in sampler.rs.
+const SAMPLER_RANDOM: u32 = 0u;
+const SAMPLER_SOBOL: u32 = 1u;
+const SAMPLER_BLUE_NOISE: u32 = 2u;
+
+// The first four dimensions of the Sobol sequence, same as `SOBOL_DIRECTIONS` in sampler.rs.
+const SOBOL_DIRECTIONS = array(
+  array(
+    0x80000000u, 0x40000000u, 0x20000000u, 0x10000000u, 0x08000000u, 0x04000000u, 0x02000000u,
+    0x01000000u, 0x00800000u, 0x00400000u, 0x00200000u, 0x00100000u, 0x00080000u, 0x00040000u,
+    0x00020000u, 0x00010000u, 0x00008000u, 0x00004000u, 0x00002000u, 0x00001000u, 0x00000800u,
+    0x00000400u, 0x00000200u, 0x00000100u, 0x00000080u, 0x00000040u, 0x00000020u, 0x00000010u,
+    0x00000008u, 0x00000004u, 0x00000002u, 0x00000001u,
+  ),
+  array(
+    0x80000000u, 0xc0000000u, 0xa0000000u, 0xf0000000u, 0x88000000u, 0xcc000000u, 0xaa000000u,
+    0xff000000u, 0x80800000u, 0xc0c00000u, 0xa0a00000u, 0xf0f00000u, 0x88880000u, 0xcccc0000u,
+    0xaaaa0000u, 0xffff0000u, 0x80008000u, 0xc000c000u, 0xa000a000u, 0xf000f000u, 0x88008800u,
+    0xcc00cc00u, 0xaa00aa00u, 0xff00ff00u, 0x80808080u, 0xc0c0c0c0u, 0xa0a0a0a0u, 0xf0f0f0f0u,
+    0x88888888u, 0xccccccccu, 0xaaaaaaaau, 0xffffffffu,
+  ),
+  array(
+    0x80000000u, 0xc0000000u, 0x60000000u, 0x90000000u, 0xe8000000u, 0x5c000000u, 0x8e000000u,
+    0xc5000000u, 0x68800000u, 0x9cc00000u, 0xee600000u, 0x55900000u, 0x80680000u, 0xc09c0000u,
+    0x60ee0000u, 0x90550000u, 0xe8808000u, 0x5cc0c000u, 0x8e606000u, 0xc5909000u, 0x6868e800u,
+    0x9c9c5c00u, 0xeeee8e00u, 0x5555c500u, 0x8000e880u, 0xc0005cc0u, 0x60008e60u, 0x9000c590u,
+    0xe8006868u, 0x5c009c9cu, 0x8e00eeeeu, 0xc5005555u,
+  ),
+  array(
+    0x80000000u, 0xc0000000u, 0x20000000u, 0x50000000u, 0xf8000000u, 0x74000000u, 0xa2000000u,
+    0x93000000u, 0xd8800000u, 0x25400000u, 0x59e00000u, 0xe6d00000u, 0x78080000u, 0xb40c0000u,
+    0x82020000u, 0xc3050000u, 0x208f8000u, 0x51474000u, 0xfbea2000u, 0x75d93000u, 0xa0858800u,
+    0x914e5400u, 0xdbe79e00u, 0x25db6d00u, 0x58800080u, 0xe54000c0u, 0x79e00020u, 0xb6d00050u,
+    0x800800f8u, 0xc00c0074u, 0x200200a2u, 0x50050093u,
+  ),
 );
 
+// The R2 sequence in 32-bit fixed point, same as `R2_GENERATORS` in sampler.rs.
+const R2_GENERATORS = vec2(0xc13fa9a9u, 0x91e10da6u);
+
+// Width and height of `blue_noise`, same as `BLUE_NOISE_SIZE` in sampler.rs.
+const BLUE_NOISE_SIZE = 64u;
+
+// The sample being traced: the pixel, the index of the sample in it and the next dimension that
+// `rand_f32` returns, and the state of xorshift32 for `SAMPLER_RANDOM`.
 struct Rng {
   state: u32,
+  pixel: vec2u,
+  index: u32,
+  dimension: u32,
 };
 var<private> rng: Rng;
 
@@ -39,6 +121,18 @@ struct Scatter{
   scattered_ray: Ray,
   intersection : Intersection,
   colors: vec3f,
+  // The solid angle density with which the scattered direction was sampled. Not meaningful for
+  // specular scattering, which follows a single direction.
+  pdf: f32,
+  is_specular: bool,
+};
+
+// A direction sampled from a BSDF in the local frame of the surface, with its throughput weight
+// (BSDF * cos / pdf) and solid angle density.
+struct BsdfSample {
+  direction: vec3f,
+  weight: vec3f,
+  pdf: f32,
 };
 
 struct Sphere{
@@ -47,10 +141,62 @@ struct Sphere{
   material: u32,
 };
 
+struct Vertex {
+  position: vec3f,
+  normal: vec3f,
+};
+
+struct Triangle {
+  indices: vec3u,
+  material: u32,
+};
+
+// For interior nodes `primitive_count` is 0 and `left_or_first` is the index of the left child,
+// which is directly followed by the right child. For leaves `left_or_first` is the index of the
+// first primitive of the leaf in `bvh_primitives`.
+struct BvhNode {
+  aabb_min: vec3f,
+  left_or_first: u32,
+  aabb_max: vec3f,
+  primitive_count: u32,
+};
+
 struct Intersection {
   normal: vec3f,
   t: f32,
   material_index: u32,
+  // The id of the primitive that was hit, in the same encoding as `bvh_primitives`.
+  primitive: u32,
+};
+
+// An emissive primitive. `cdf` is the summed power of this light and all lights before it.
+struct Light {
+  primitive: u32,
+  cdf: f32,
+};
+
+// A point sampled on the surface of a light.
+struct LightSample {
+  position: vec3f,
+  normal: vec3f,
+  emission: vec3f,
+  // Probability density of having picked the point with respect to solid angle as seen from the
+  // shaded point.
+  pdf: f32,
+};
+
+// A direction towards the environment map or the sun and the radiance arriving from it.
+struct EnvironmentSample {
+  direction: vec3f,
+  radiance: vec3f,
+  // Probability density of having picked the direction with respect to solid angle.
+  pdf: f32,
+};
+
+// An outcome picked from a CDF, with the position of the random number within its step.
+struct CdfSample {
+  index: u32,
+  remapped: f32,
 };
 
 struct CameraUniforms {
@@ -58,14 +204,57 @@ struct CameraUniforms {
     u: vec3f,
     v: vec3f,
     w: vec3f,
+    // The fourth component of `w` in camera.rs. Explicit rather than an `@align(16)` on the next
+    // field, which the GL backend ignores, packing the field into the end of the vec3.
+    _padding_w: f32,
+    // The thin lens, see `Camera` in camera.rs.
+    aperture_radius: f32,
+    focus_distance: f32,
+    aperture_blades: u32,
+    projection: u32,
+    // Vertical field of view in radians.
+    vfov: f32,
+    // The plane that orthographic views frame, which focusing leaves where it is.
+    orthographic_distance: f32,
+};
+
+// The physical sky, see `SkyUniforms` in sky.rs.
+struct SkyUniforms {
+  // Coefficients A to E of the Perez distribution of Y, x and y, in xyz.
+  perez: array<vec4f, 5>,
+  // Y, x and y at the zenith divided by the Perez function at the zenith.
+  zenith: vec4f,
+  sun_direction: vec3f,
+  cos_sun_radius: f32,
+  sun_radiance: vec3f,
 };
 
 struct Uniforms {
   camera: CameraUniforms,
+  sky: SkyUniforms,
   width: u32,
   height: u32,
   frame_count: u32,
-  _padding: u32,
+  light_sampling: u32,
+  samples_per_frame: u32,
+  sample_count: u32,
+  tone_mapping: u32,
+  // Factor that radiance is scaled by before tone mapping, 2^EV.
+  exposure: f32,
+  // Non-zero if `environment_map` replaces the sky gradient.
+  environment: u32,
+  // Rotation of the environment around the y axis in radians, counterclockwise seen from above.
+  environment_rotation: f32,
+  // Factor that the radiance of the environment is scaled by.
+  environment_intensity: f32,
+  // Non-zero if the physical sky in `sky` replaces the sky gradient.
+  physical_sky: u32,
+  // Longest path, in bounces.
+  max_bounces: u32,
+  // Number of bounces after which paths are subject to Russian roulette, or 0xffffffff for none.
+  roulette_depth: u32,
+  // How the numbers of the samples are generated, one of the `SAMPLER_*` constants.
+  sample_generator: u32,
 };
 
 struct Ray {
@@ -76,14 +265,29 @@ struct Ray {
 struct Material{
   colour : vec3f,
   type_mat : u32,
+  // Emitted radiance. Only non-zero for emissive materials.
+  emission : vec3f,
   refractive_index : f32,
+  // Beer-Lambert absorption coefficient of the interior of a closed dielectric, per unit distance.
+  absorption : vec3f,
+  // GGX roughness of a metal or principled material. 0 is a perfect mirror.
+  roughness : f32,
+  // Parameters of principled materials, see `MaterialKind::Principled` in scene.rs.
+  metallic : f32,
+  specular : f32,
+  clearcoat : f32,
+  transmission : f32,
 };
 
 //add type parameter, modify scatter to check parameter, 2 different materials
-fn init_rng(pixel: vec2u) {
-  // Seed the PRNG using the scalar index of the pixel and the current frame count.
-  let seed = (pixel.x + pixel.y * uniforms.width) ^ jenkins_hash(uniforms.frame_count);
+fn init_rng(pixel: vec2u, sample_index: u32) {
+  // Seed the PRNG using the scalar index of the pixel and the index of the sample, which is unique
+  // across frames.
+  let seed = (pixel.x + pixel.y * uniforms.width) ^ jenkins_hash(sample_index);
   rng.state = jenkins_hash(seed);
+  rng.pixel = pixel;
+  rng.index = sample_index;
+  rng.dimension = 0u;
 }
 
 // A slightly modified version of the "One-at-a-Time Hash" function by Bob Jenkins.
@@ -108,77 +312,492 @@ fn xorshift32() -> u32 {
   return x;
 }
 
+// Returns the next dimension of the sample, a number in [0, 1) from the generator selected by
+// `uniforms.sample_generator`.
 fn rand_f32() -> f32 {
-  return bitcast<f32>(0x3f800000u | (xorshift32() >> 9u)) - 1.;
+  var bits: u32;
+  switch uniforms.sample_generator {
+    case SAMPLER_SOBOL: {
+      bits = sobol_sample(rng.dimension);
+    }
+    case SAMPLER_BLUE_NOISE: {
+      bits = blue_noise_sample(rng.dimension);
+    }
+    default: {
+      bits = xorshift32();
+    }
+  }
+  rng.dimension += 1u;
+  return bitcast<f32>(0x3f800000u | (bits >> 9u)) - 1.;
+}
+
+// A dimension of the Owen scrambled Sobol sequence. The dimensions are drawn four at a time from
+// the first four of the sequence, shuffled and scrambled independently for each set of four and
+// each pixel. See Brent Burley, "Practical Hash-based Owen Scrambling", JCGT 2020.
+fn sobol_sample(dimension: u32) -> u32 {
+  let seed = hash_combine(rng.pixel.x + rng.pixel.y * uniforms.width, dimension / 4u);
+  let index = nested_uniform_scramble(rng.index, seed);
+  let component = dimension % 4u;
+  var x = 0u;
+  for (var bit = 0u; bit < 32u; bit += 1u) {
+    if ((index >> bit) & 1u) != 0u {
+      x ^= SOBOL_DIRECTIONS[component][bit];
+    }
+  }
+  return nested_uniform_scramble(x, hash_combine(seed, component));
 }
 
-@fragment fn path_tracer_fs(@builtin(position) pos: vec4f) -> @location(0) vec4f {
+// A dimension of the R2 sequence rotated by the blue noise texture. The texture is shifted and the
+// points are shuffled differently for each pair of dimensions so that the pairs are not
+// correlated, see `blue_noise_sample` in sampler.rs.
+fn blue_noise_sample(dimension: u32) -> u32 {
+  let seed = jenkins_hash(dimension / 2u);
+  let texel = (rng.pixel + vec2(seed, seed >> 6u)) % BLUE_NOISE_SIZE;
+  let component = dimension % 2u;
+  let rotation = textureLoad(blue_noise, texel, 0)[component];
+  let index = nested_uniform_scramble(rng.index, seed);
+  return rotation + index * R2_GENERATORS[component];
+}
 
-  init_rng(vec2u(pos.xy));
+fn hash_combine(seed: u32, value: u32) -> u32 {
+  return jenkins_hash(seed ^ jenkins_hash(value));
+}
 
-  let origin = uniforms.camera.origin.xyz;
-  let focal_length = 1.;
+// An Owen scrambling of the bits of `x`, where each bit is flipped depending on the bits above it.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+  return reverseBits(laine_karras_permutation(reverseBits(x), seed));
+}
+
+// A hash in which each bit only depends on the bits below it, from Burley's paper.
+fn laine_karras_permutation(x: u32, seed: u32) -> u32 {
+  var y = x + seed;
+  y ^= y * 0x6c50b47cu;
+  y ^= y * 0xb82f1e52u;
+  y ^= y * 0xc7afe638u;
+  y ^= y * 0x8d22f6e6u;
+  return y;
+}
+
+// The path statistics of a workgroup, which are added to `path_statistics` once all of its paths
+// have been traced.
+var<workgroup> workgroup_path_counts: array<atomic<u32>, PATH_COUNTERS>;
+
+// Traces `uniforms.samples_per_frame` paths through each pixel and adds their radiance to the sum
+// of the previous frames.
+@compute @workgroup_size(WORKGROUP_SIZE, WORKGROUP_SIZE)
+fn path_tracer_cs(
+  @builtin(global_invocation_id) id: vec3u,
+  @builtin(local_invocation_index) local_index: u32,
+) {
+  // The image is covered by whole workgroups, which can extend past its edges.
+  if id.x < uniforms.width && id.y < uniforms.height {
+    trace_pixel(id.xy);
+  }
+
+  // Add the counts of the workgroup with one atomic operation per counter rather than per path.
+  // The totals are 64-bit, carrying into the second word of each pair.
+  workgroupBarrier();
+  for (var i = local_index; i < PATH_COUNTERS; i += WORKGROUP_SIZE * WORKGROUP_SIZE) {
+    let count = atomicLoad(&workgroup_path_counts[i]);
+    if count > 0u {
+      let old = atomicAdd(&path_statistics[2u * i], count);
+      if old + count < old {
+        atomicAdd(&path_statistics[2u * i + 1u], 1u);
+      }
+    }
+  }
+}
+
+// Traces the samples of a pixel of the image and adds them to its sum.
+fn trace_pixel(pixel: vec2u) {
+  let pos = vec2f(pixel) + 0.5;
+
+  var radiance_sample = vec3(0.);
+  let first_sample = uniforms.sample_count - uniforms.samples_per_frame;
+  for (var i = 0u; i < uniforms.samples_per_frame; i += 1u) {
+    init_rng(pixel, first_sample + i);
+    radiance_sample += trace_path(pos);
+  }
+
+  // Fetch the old sum of samples.
+  var old_sum: vec3f;  if uniforms.frame_count > 1 {
+    old_sum = textureLoad(radiance_samples_old, pixel, 0).xyz;
+  } else {
+    old_sum = vec3(0.);
+  }
+
+  // Compute and store the new sum.
+  let new_sum = radiance_sample + old_sum;
+  textureStore(radiance_samples_new, pixel, vec4(new_sum, 0.));
+}
+
+// Returns the radiance carried by a path through a random point of the pixel centred at `pos`.
+fn trace_path(pos: vec2f) -> vec3f {
   let offset = vec2(rand_f32() - 0.5, rand_f32() - 0.5);
-  var uv = (pos.xy + offset) / vec2f(f32(uniforms.width - 1u), f32(uniforms.height - 1u));
+  var uv = (pos.xy + offset) / vec2f(f32(uniforms.width), f32(uniforms.height));
   uv = (2.*uv - 1.)*vec2(f32(uniforms.width)/ f32(uniforms.height), -1.0);
-  let camera_rotation = mat3x3(uniforms.camera.u, uniforms.camera.v, uniforms.camera.w);
-  let direction = camera_rotation * vec3(uv, focal_length);
-  var ray = Ray(origin, direction);
+  var ray = camera_ray(uv);
+  // Outside the image circle of a fisheye lens.
+  if all(ray.direction == vec3(0.)) {
+    return vec3(0.);
+  }
+  if uniforms.camera.aperture_radius > 0. && uses_focal_plane(uniforms.camera.projection) {
+    // A thin lens focuses the rays through all of its points on the point of the plane in focus
+    // that the ray through its centre hits.
+    let focus_point = ray.origin + ray.direction * uniforms.camera.focus_distance;
+    let lens = uniforms.camera.aperture_radius * sample_aperture(uniforms.camera.aperture_blades);
+    ray.origin += lens.x * uniforms.camera.u + lens.y * uniforms.camera.v;
+    ray.direction = focus_point - ray.origin;
+  }
   var through_put = vec3(1.);
+  var radiance_sample = vec3(0.);
+  let light_sampling = uniforms.light_sampling != 0u;
+
+  // The previous scattering event, used to weight emission found by BSDF sampling. The camera ray
+  // is treated like a specular bounce since it cannot be found by light sampling.
+  var last_pdf = 0.;
+  var last_specular = true;
 
-  for(var bounce = 0u; bounce < MAX_BOUNCES; bounce += 1u) {
+  // How the path ended, for `path_statistics`.
+  var bounces = uniforms.max_bounces;
+  var terminated_by_roulette = false;
+
+  for(var bounce = 0u; bounce < uniforms.max_bounces; bounce += 1u) {
     let hit = closest_hit(ray);
     if is_valid_intersection(hit) {
+      // Light emitted by the surface reaches the camera attenuated by the path so far. When light
+      // sampling is enabled the same light could also have been found by the previous vertex, so
+      // both estimates are combined with multiple importance sampling.
+      let material = materials[hit.material_index];
+
+      // The ray travelled inside the object if it hit the back of its surface.
+      if dot(ray.direction, hit.normal) > 0. {
+        through_put *= exp(-material.absorption * hit.t * length(ray.direction));
+      }
+
+      var emission_weight = 1.;
+      if light_sampling && !last_specular && any(material.emission > vec3(0.)) {
+        let light_pdf = light_pdf(hit.primitive, ray, hit.t);
+        emission_weight = power_heuristic(last_pdf, light_pdf);
+      }
+      radiance_sample += through_put * material.emission * emission_weight;
+      if material.type_mat == MATERIAL_EMISSIVE {
+        bounces = bounce;
+        break;
+      }
+
+      if light_sampling && !is_specular(material) {
+        let front_face = dot(ray.direction, hit.normal) < 0.;
+        let normal = select(-hit.normal, hit.normal, front_face);
+        let hit_point = point_on_ray(ray, hit.t);
+        let wo = -normalize(ray.direction);
+        radiance_sample +=
+            through_put * sample_direct_light(hit_point, normal, wo, material, front_face);
+      }
+
       let scatter = scatter_ray(ray, hit);
       through_put = through_put * scatter.colors;
       ray = scatter.scattered_ray;
+      last_pdf = scatter.pdf;
+      last_specular = scatter.is_specular;
+
+      // Russian roulette: end the path with a probability that grows as its throughput drops, and
+      // divide the throughput of the paths that go on by the probability of going on, so that the
+      // estimate stays unbiased. Paths whose throughput is zero always end.
+      if bounce + 1u >= uniforms.roulette_depth {
+        let survival = min(max(through_put.x, max(through_put.y, through_put.z)), 1.);
+        if rand_f32() >= survival {
+          bounces = bounce + 1u;
+          terminated_by_roulette = true;
+          break;
+        }
+        through_put /= survival;
+      }
     } else {
-      let sky = sky_color(ray);
-      through_put = through_put * sky;
+      // An environment map and the sun are also found by light sampling at the previous vertex.
+      var sky = sky_color(ray);
+      var sun = sun_color(ray);
+      if light_sampling && !last_specular {
+        if uniforms.environment != 0u {
+          sky *= power_heuristic(last_pdf, environment_pdf(normalize(ray.direction)));
+        }
+        sun *= power_heuristic(last_pdf, sun_pdf());
+      }
+      radiance_sample += through_put * (sky + sun);
+      bounces = bounce;
       break;
     }
   }
 
-  var radiance_sample: vec3f;
-  radiance_sample = through_put;
-  
+  atomicAdd(&workgroup_path_counts[bounces], 1u);
+  if terminated_by_roulette {
+    atomicAdd(&workgroup_path_counts[MAX_BOUNCE_LIMIT + 1u + bounces], 1u);
+  }
+  return radiance_sample;
+}
 
-  // Fetch the old sum of samples.
-  var old_sum: vec3f;  if uniforms.frame_count > 1 {
-    old_sum = textureLoad(radiance_samples_old, vec2u(pos.xy), 0).xyz;
+@vertex fn display_vs(@builtin(vertex_index) vid: u32) -> @builtin(position) vec4f {
+  return vec4f(quad_vertices[vid], 0.0, 1.0);
+}
+
+// Displays the average of the samples traced so far. The CPU version is `display_transform` in
+// tonemap.rs.
+@fragment fn display_fs(@builtin(position) pos: vec4f) -> @location(0) vec4f {
+  let sum = textureLoad(radiance_sum, vec2u(pos.xy), 0).xyz;
+  let radiance = sum / f32(max(uniforms.sample_count, 1u));
+  let col = tone_map(uniforms.exposure * radiance, uniforms.tone_mapping);
+  if ENCODE_GAMMA {
+    return vec4(pow(clamp(col, vec3(0.), vec3(1.)), vec3(1.0/2.2)), 1.);
+  }
+  return vec4(col, 1.);
+}
+
+// Maps linear radiance to linear display values, which are in [0, 1] for all operators except
+// `TONE_MAPPING_LINEAR`.
+fn tone_map(radiance: vec3f, tone_mapping: u32) -> vec3f {
+  let colour = max(radiance, vec3(0.));
+  switch tone_mapping {
+    case TONE_MAPPING_REINHARD: {
+      return colour / (1. + colour);
+    }
+    case TONE_MAPPING_EXTENDED_REINHARD: {
+      let l = dot(colour, vec3(0.2126, 0.7152, 0.0722));
+      if l <= 0. {
+        return vec3(0.);
+      }
+      let white2 = EXTENDED_REINHARD_WHITE * EXTENDED_REINHARD_WHITE;
+      let mapped = l * (1. + l / white2) / (1. + l);
+      return min(colour * mapped / l, vec3(1.));
+    }
+    case TONE_MAPPING_ACES: {
+      // Krzysztof Narkowicz's fit of the ACES filmic curve.
+      let c = 0.6 * colour;
+      return clamp((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14), vec3(0.), vec3(1.));
+    }
+    case TONE_MAPPING_AGX: {
+      return agx(colour);
+    }
+    default: {
+      return colour;
+    }
+  }
+}
+
+// Troy Sobotka's AgX with Benjamin Wrensch's polynomial approximation of the contrast curve.
+// See https://iolite-engine.com/blog_posts/minimal_agx_implementation
+fn agx(colour: vec3f) -> vec3f {
+  let inset = mat3x3f(
+    0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+    0.0784335999999992, 0.878468636469772, 0.0784336,
+    0.0792237451477643, 0.0791661274605434, 0.879142973793104,
+  );
+  let outset = mat3x3f(
+    1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+    -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+    -0.0990297440797205, -0.0989611768448433, 1.15107367264116,
+  );
+  let min_ev = -12.47393;
+  let max_ev = 4.026069;
+
+  let x = (clamp(log2(max(inset * colour, vec3(1e-10))), vec3(min_ev), vec3(max_ev)) - min_ev)
+      / (max_ev - min_ev);
+  let x2 = x * x;
+  let x4 = x2 * x2;
+  let encoded = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2
+      + 0.1191 * x - 0.00232;
+  // The curve produces display encoded values, which are linearized for the display transform.
+  return pow(clamp(outset * encoded, vec3(0.), vec3(1.)), vec3(2.2));
+}
+
+@group(0) @binding(0) var<uniform> uniforms: Uniforms;
+@group(0) @binding(1) var radiance_samples_old :texture_2d<f32>;
+@group(0) @binding(2) var radiance_samples_new : texture_storage_2d<rgba32float, write>;
+@group(0) @binding(3) var<storage, read> spheres: array<Sphere>;
+@group(0) @binding(4) var<storage, read> materials: array<Material>;
+@group(0) @binding(5) var<storage, read> vertices: array<Vertex>;
+@group(0) @binding(6) var<storage, read> triangles: array<Triangle>;
+@group(0) @binding(7) var<storage, read> bvh_nodes: array<BvhNode>;
+@group(0) @binding(8) var<storage, read> bvh_primitives: array<u32>;
+@group(0) @binding(9) var<storage, read> lights: array<Light>;
+@group(0) @binding(10) var environment_map: texture_2d<f32>;
+// The distribution that `sample_environment` draws from, see `EnvironmentDistribution::texels` in
+// environment.rs. It has one more column than `environment_map`, holding the marginal CDF.
+@group(0) @binding(11) var environment_cdf: texture_2d<f32>;
+// Counters of how paths ended, see `PATH_COUNTERS`, as pairs of the low and the high word of 64-bit
+// totals. Cleared by render.rs whenever accumulation restarts.
+@group(0) @binding(12) var<storage, read_write> path_statistics: array<atomic<u32>>;
+// Rotations of `SAMPLER_BLUE_NOISE` in 32-bit fixed point, see `blue_noise_texture` in sampler.rs.
+@group(0) @binding(13) var blue_noise: texture_2d<u32>;
+
+// The display pass has its own bind group, with the sum written by the last trace at a binding
+// that the trace pass does not use. Same as `RADIANCE_SUM_BINDING` in render.rs.
+@group(0) @binding(32) var radiance_sum: texture_2d<f32>;
+
+// Radiance arriving from infinitely far away along `ray`, from the environment map or the physical
+// sky if the scene has one and from a gradient otherwise. The sun is left to `sun_color`.
+fn sky_color(ray: Ray) -> vec3f {
+  let direction = normalize(ray.direction);
+  var radiance: vec3f;
+  if uniforms.environment != 0u {
+    radiance = environment_radiance(direction);
+  } else if uniforms.physical_sky != 0u {
+    radiance = physical_sky_radiance(direction);
   } else {
-    old_sum = vec3(0.);
+    let t = 0.5 * (direction.y + 1.);
+    radiance = (1. - t) * vec3(1.) + t * vec3(0.3, 0.5, 1.);
   }
+  return uniforms.environment_intensity * radiance;
+}
 
-  // Compute and store the new sum.
-  let new_sum = radiance_sample + old_sum;
-  textureStore(radiance_samples_new, vec2u(pos.xy), vec4(new_sum, 0.));
+// Radiance of the sky of the Preetham model towards the unit vector `direction`, without the sun.
+// The CPU version is `Sky::radiance`.
+fn physical_sky_radiance(direction: vec3f) -> vec3f {
+  let sky = uniforms.sky;
+  let cos_gamma = clamp(dot(direction, sky.sun_direction), -1., 1.);
+  let gamma = acos(cos_gamma);
+  // Directions below the horizon get the value at the horizon.
+  let gradation = 1. + sky.perez[0].xyz * exp(sky.perez[1].xyz / max(direction.y, 1e-3));
+  let perez = gradation * (1. + sky.perez[2].xyz * exp(sky.perez[3].xyz * gamma)
+      + sky.perez[4].xyz * cos_gamma * cos_gamma);
+  let yxy = sky.zenith.xyz * perez;
+  if yxy.z <= 0. {
+    return vec3(0.);
+  }
+  // Luminance and chromaticity to CIE XYZ, then to linear sRGB.
+  let xyz = vec3(yxy.y / yxy.z * yxy.x, yxy.x, (1. - yxy.y - yxy.z) / yxy.z * yxy.x);
+  let rgb = mat3x3f(
+    3.2406, -0.9689, 0.0557,
+    -1.5372, 1.8758, -0.2040,
+    -0.4986, 0.0415, 1.0570,
+  ) * xyz;
+  return max(rgb, vec3(0.));
+}
 
-  
+// Radiance arriving from the disk of the sun of the physical sky along `ray`, if it points at it.
+fn sun_color(ray: Ray) -> vec3f {
+  let sky = uniforms.sky;
+  let cos_theta = dot(normalize(ray.direction), sky.sun_direction);
+  if uniforms.physical_sky == 0u || cos_theta < sky.cos_sun_radius {
+    return vec3(0.);
+  }
+  return uniforms.environment_intensity * sky.sun_radiance;
+}
 
-  // Display the average.
-let col = new_sum / f32(uniforms.frame_count);
-return vec4(pow(col, vec3(1.0/2.2)), 1.);
+// The density with which `sample_sun` picks a direction towards the disk of the sun, with respect
+// to solid angle.
+fn sun_pdf() -> f32 {
+  return 1. / (2. * PI * (1. - uniforms.sky.cos_sun_radius));
+}
 
+// Picks a uniformly distributed direction towards the disk of the sun.
+fn sample_sun() -> EnvironmentSample {
+  let sky = uniforms.sky;
+  let cos_theta = 1. - rand_f32() * (1. - sky.cos_sun_radius);
+  let sin_theta = sqrt(max(1. - cos_theta * cos_theta, 0.));
+  let phi = 2. * PI * rand_f32();
+  let local = vec3(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+  let direction = orthonormal_basis(sky.sun_direction) * local;
+  return EnvironmentSample(direction, uniforms.environment_intensity * sky.sun_radiance, sun_pdf());
+}
 
+// Looks up the texel of the environment map towards the unit vector `direction`. Texels are not
+// interpolated.
+fn environment_radiance(direction: vec3f) -> vec3f {
+  return textureLoad(environment_map, environment_texel(direction), 0).rgb;
 }
-@vertex fn path_tracer_vs(@builtin(vertex_index) vid: u32) -> @builtin(position) vec4f {
-  return vec4f(vertices[vid], 0.0, 1.0);
+
+// The texel of the environment map towards the unit vector `direction`. The map is
+// equirectangular, see `EnvironmentMap` in environment.rs.
+fn environment_texel(direction: vec3f) -> vec2u {
+  let size = textureDimensions(environment_map);
+  let longitude = atan2(direction.x, -direction.z) + uniforms.environment_rotation;
+  let u = fract(0.5 + longitude / (2. * PI));
+  let v = acos(clamp(direction.y, -1., 1.)) / PI;
+  return min(vec2u(vec2f(u, v) * vec2f(size)), size - 1u);
 }
 
+// Value `i` of the conditional CDF of the columns of row `row` of the environment map, or of the
+// marginal CDF of the rows if `row` is the height of the map.
+fn environment_cdf_value(row: u32, i: u32) -> f32 {
+  let size = textureDimensions(environment_cdf);
+  if row == size.y {
+    return textureLoad(environment_cdf, vec2u(size.x - 1u, i), 0).r;
+  }
+  return textureLoad(environment_cdf, vec2u(i, row), 0).r;
+}
 
+// The probability of outcome `i` of a CDF of `environment_cdf`, see `environment_cdf_value`.
+fn environment_cdf_step(row: u32, i: u32) -> f32 {
+  var below = 0.;
+  if i > 0u {
+    below = environment_cdf_value(row, i - 1u);
+  }
+  return environment_cdf_value(row, i) - below;
+}
 
-@group(0) @binding(0) var<uniform> uniforms: Uniforms;
-@group(0) @binding(1) var radiance_samples_old :texture_2d<f32>;
-@group(0) @binding(2) var radiance_samples_new : texture_storage_2d<rgba32float, write>;
+// Picks the first of the `count` outcomes of a CDF of `environment_cdf` whose value is above `u`.
+// The CPU version is `sample_cdf` in environment.rs.
+fn sample_cdf(row: u32, count: u32, u: f32) -> CdfSample {
+  var lo = 0u;
+  var hi = count - 1u;
+  while lo < hi {
+    let mid = (lo + hi) / 2u;
+    if environment_cdf_value(row, mid) <= u {
+      lo = mid + 1u;
+    } else {
+      hi = mid;
+    }
+  }
+  var below = 0.;
+  if lo > 0u {
+    below = environment_cdf_value(row, lo - 1u);
+  }
+  let step = environment_cdf_value(row, lo) - below;
+  if step <= 0. {
+    return CdfSample(lo, 0.5);
+  }
+  return CdfSample(lo, min((u - below) / step, 0.99999994));
+}
 
-fn sky_color(ray: Ray) -> vec3f {
-  let t = 0.5 * (normalize(ray.direction).y + 1.);
-  return (1. - t) * vec3(1.) + t * vec3(0.3, 0.5, 1.);
+// Picks a direction towards the environment map with probability proportional to the luminance of
+// its texels times the solid angle that they cover. The CPU version is
+// `EnvironmentDistribution::sample`.
+fn sample_environment() -> EnvironmentSample {
+  let size = textureDimensions(environment_cdf);
+  let width = size.x - 1u;
+  let height = size.y;
+  let u = vec2(rand_f32(), rand_f32());
+  let row = sample_cdf(height, height, u.y);
+  let column = sample_cdf(row.index, width, u.x);
+  let uv = (vec2(f32(column.index), f32(row.index)) + vec2(column.remapped, row.remapped))
+      / vec2(f32(width), f32(height));
+  // The inverse of the mapping in `environment_texel`.
+  let longitude = 2. * PI * (uv.x - 0.5) - uniforms.environment_rotation;
+  let theta = PI * uv.y;
+  let direction = vec3(sin(theta) * sin(longitude), cos(theta), -sin(theta) * cos(longitude));
+  let radiance = uniforms.environment_intensity * environment_radiance(direction);
+  return EnvironmentSample(direction, radiance, environment_pdf(direction));
+}
+
+// The density with which `sample_environment` picks the unit vector `direction`, with respect to
+// solid angle. The CPU version is `EnvironmentDistribution::pdf`.
+fn environment_pdf(direction: vec3f) -> f32 {
+  let size = textureDimensions(environment_cdf);
+  let width = size.x - 1u;
+  let height = size.y;
+  let sin_theta = sqrt(max(1. - direction.y * direction.y, 0.));
+  if sin_theta == 0. {
+    return 0.;
+  }
+  let texel = environment_texel(direction);
+  let probability = environment_cdf_step(height, texel.y) * environment_cdf_step(texel.y, texel.x);
+  // The density over the map scaled to [0, 1]^2 divided by the Jacobian of the mapping to
+  // directions, 2 pi^2 sin(theta).
+  return probability * f32(width * height) / (2. * PI * PI * sin_theta);
 }
 
 fn no_intersection() -> Intersection {
-  return Intersection(vec3(0.), -1., 0u);
+  return Intersection(vec3(0.), -1., 0u, 0u);
 }
 fn point_on_ray(ray: Ray, t: f32) -> vec3<f32> {
   return ray.origin + t * ray.direction;
@@ -191,6 +810,88 @@ fn sample_sphere() -> vec3f {
   let phi = 2*PI * r1;
   return vec3(xz_r * cos(phi), y, xz_r * sin(phi));
 }
+
+// Returns the ray through the centre of the lens and the point `uv` of the image, which spans
+// [-1, 1] vertically and [-aspect ratio, aspect ratio] horizontally, with y up. For projections
+// onto a plane, the component of the direction along the view direction is 1. The direction is
+// zero where the projection does not cover the image. The CPU version is `Camera::ray_through`.
+fn camera_ray(uv: vec2f) -> Ray {
+  let camera = uniforms.camera;
+  let half_vfov = 0.5 * camera.vfov;
+  let tan_half_vfov = tan(min(half_vfov, MAX_PLANAR_HALF_VFOV));
+  switch camera.projection {
+    case PROJECTION_ORTHOGRAPHIC: {
+      // The plane at `orthographic_distance` is framed like it is by the perspective projection.
+      let p = uv * tan_half_vfov * camera.orthographic_distance;
+      return Ray(camera.origin + p.x * camera.u + p.y * camera.v, camera.w);
+    }
+    case PROJECTION_EQUIRECTANGULAR: {
+      // Longitude and latitude span the whole sphere regardless of the field of view.
+      let aspect_ratio = f32(uniforms.width) / f32(uniforms.height);
+      let longitude = PI * uv.x / aspect_ratio;
+      let latitude = 0.5 * PI * uv.y;
+      let horizontal = sin(longitude) * camera.u + cos(longitude) * camera.w;
+      return Ray(camera.origin, cos(latitude) * horizontal + sin(latitude) * camera.v);
+    }
+    case PROJECTION_FISHEYE: {
+      // Equidistant: the angle from the view direction grows linearly with the distance from
+      // the centre of the image, reaching half the field of view at the top and bottom edges.
+      let r = length(uv);
+      let theta = r * half_vfov;
+      if r == 0. {
+        return Ray(camera.origin, camera.w);
+      }
+      if theta > PI {
+        return Ray(camera.origin, vec3(0.));
+      }
+      let p = sin(theta) * uv / r;
+      return Ray(camera.origin, p.x * camera.u + p.y * camera.v + cos(theta) * camera.w);
+    }
+    default: {
+      let p = uv * tan_half_vfov;
+      return Ray(camera.origin, p.x * camera.u + p.y * camera.v + camera.w);
+    }
+  }
+}
+
+// Whether a projection has a plane in focus, which a thin lens needs.
+fn uses_focal_plane(projection: u32) -> bool {
+  return projection == PROJECTION_PERSPECTIVE || projection == PROJECTION_ORTHOGRAPHIC;
+}
+
+// Returns a uniformly distributed point of the unit disk, or of a regular polygon with `blades`
+// sides inscribed in it if there are at least 3.
+fn sample_aperture(blades: u32) -> vec2f {
+  if blades < 3u {
+    let r = sqrt(rand_f32());
+    let phi = 2. * PI * rand_f32();
+    return r * vec2(cos(phi), sin(phi));
+  }
+  // The polygon is made of triangles of equal area between its centre and its sides. Pick one,
+  // then a point in it.
+  let side = min(u32(rand_f32() * f32(blades)), blades - 1u);
+  let angle = 2. * PI / f32(blades);
+  let a = vec2(cos(f32(side) * angle), sin(f32(side) * angle));
+  let b = vec2(cos(f32(side + 1u) * angle), sin(f32(side + 1u) * angle));
+  return sqrt(rand_f32()) * mix(a, b, rand_f32());
+}
+
+// Returns a direction in the hemisphere around +z, distributed proportionally to its cosine with z.
+fn sample_cosine_hemisphere() -> vec3f {
+  let r = sqrt(rand_f32());
+  let phi = 2. * PI * rand_f32();
+  return vec3(r * cos(phi), r * sin(phi), sqrt(max(0., 1. - r * r)));
+}
+// Returns a rotation that maps +z to the unit vector `n`. See Duff et al., "Building an
+// Orthonormal Basis, Revisited", JCGT 2017.
+fn orthonormal_basis(n: vec3f) -> mat3x3f {
+  let s = select(-1., 1., n.z >= 0.);
+  let a = -1. / (s + n.z);
+  let b = n.x * n.y * a;
+  let tangent = vec3(1. + s * n.x * n.x * a, s * b, -s * n.x);
+  let bitangent = vec3(b, s + n.y * n.y * a, -n.y);
+  return mat3x3(tangent, bitangent, n);
+}
 fn intersect_sphere(ray: Ray, sphere: Sphere) -> Intersection {
   let v = ray.origin - sphere.center;
   let a = dot(ray.direction, ray.direction);
@@ -214,16 +915,111 @@ fn intersect_sphere(ray: Ray, sphere: Sphere) -> Intersection {
 
   let p = point_on_ray(ray, t);
   let n = (p - sphere.center) / sphere.radius;
-  return Intersection(n, t, sphere.material);
+  return Intersection(n, t, sphere.material, 0u);
+}
+// Moller-Trumbore ray/triangle intersection. The returned normal is interpolated from the vertex
+// normals using the barycentric coordinates of the hit point.
+fn intersect_triangle(ray: Ray, triangle: Triangle) -> Intersection {
+  let v0 = vertices[triangle.indices.x];
+  let v1 = vertices[triangle.indices.y];
+  let v2 = vertices[triangle.indices.z];
+  let e1 = v1.position - v0.position;
+  let e2 = v2.position - v0.position;
+  let p = cross(ray.direction, e2);
+  let det = dot(e1, p);
+  if abs(det) < 1e-8 {
+    return no_intersection();
+  }
+
+  let recip_det = 1. / det;
+  let s = ray.origin - v0.position;
+  let u = dot(s, p) * recip_det;
+  if u < 0. || u > 1. {
+    return no_intersection();
+  }
+  let q = cross(s, e1);
+  let v = dot(ray.direction, q) * recip_det;
+  if v < 0. || u + v > 1. {
+    return no_intersection();
+  }
+  let t = dot(e2, q) * recip_det;
+  if t <= 0. {
+    return no_intersection();
+  }
+
+  let n = normalize((1. - u - v) * v0.normal + u * v1.normal + v * v2.normal);
+  return Intersection(n, t, triangle.material, 0u);
+}
+fn intersect_primitive(ray: Ray, id: u32) -> Intersection {
+  var hit: Intersection;
+  if (id & PRIMITIVE_TRIANGLE_BIT) != 0u {
+    hit = intersect_triangle(ray, triangles[id & ~PRIMITIVE_TRIANGLE_BIT]);
+  } else {
+    hit = intersect_sphere(ray, spheres[id]);
+  }
+  hit.primitive = id;
+  return hit;
+}
+
+// Returns the distance at which the ray enters the box, or FLT_MAX if it misses the box or only
+// enters it beyond `t_max`.
+fn intersect_aabb(ray: Ray, inv_dir: vec3f, aabb_min: vec3f, aabb_max: vec3f, t_max: f32) -> f32 {
+  let t0 = (aabb_min - ray.origin) * inv_dir;
+  let t1 = (aabb_max - ray.origin) * inv_dir;
+  let t_lo = min(t0, t1);
+  let t_hi = max(t0, t1);
+  let t_near = max(max(t_lo.x, t_lo.y), t_lo.z);
+  let t_far = min(min(t_hi.x, t_hi.y), t_hi.z);
+  if t_near <= t_far && t_far > 0. && t_near < t_max {
+    return t_near;
+  }
+  return FLT_MAX;
 }
+
 fn closest_hit(ray: Ray) -> Intersection {
-var closest = Intersection(vec3(0),FLT_MAX, 0u);
-  for (var i = 0u; i < OBJ_COUNT; i += 1u) {
-    let hit = intersect_sphere(ray, spheres[i]);
-    if hit.t > 0. && hit.t < closest.t {
-      closest = hit;
+  var closest = Intersection(vec3(0), FLT_MAX, 0u, 0u);
+  let inv_dir = 1. / ray.direction;
+
+  var stack: array<u32, BVH_STACK_SIZE>;
+  var stack_size = 0u;
+  let root = bvh_nodes[0];
+  if intersect_aabb(ray, inv_dir, root.aabb_min, root.aabb_max, FLT_MAX) < FLT_MAX {
+    stack[0] = 0u;
+    stack_size = 1u;
+  }
+
+  while stack_size > 0u {
+    stack_size -= 1u;
+    let node = bvh_nodes[stack[stack_size]];
+    if node.primitive_count > 0u {
+      for (var i = 0u; i < node.primitive_count; i += 1u) {
+        let hit = intersect_primitive(ray, bvh_primitives[node.left_or_first + i]);
+        if hit.t > 0. && hit.t < closest.t {
+          closest = hit;
+        }
+      }
+      continue;
+    }
+
+    // Visit the nearer child first by pushing it last.
+    let left = node.left_or_first;
+    let right = left + 1u;
+    let left_node = bvh_nodes[left];
+    let right_node = bvh_nodes[right];
+    let t_left = intersect_aabb(ray, inv_dir, left_node.aabb_min, left_node.aabb_max, closest.t);
+    let t_right = intersect_aabb(ray, inv_dir, right_node.aabb_min, right_node.aabb_max, closest.t);
+    let near = select(right, left, t_left < t_right);
+    let far = select(left, right, t_left < t_right);
+    if max(t_left, t_right) < FLT_MAX {
+      stack[stack_size] = far;
+      stack_size += 1u;
+    }
+    if min(t_left, t_right) < FLT_MAX {
+      stack[stack_size] = near;
+      stack_size += 1u;
     }
   }
+
   if(closest.t == FLT_MAX){
     return no_intersection();
   }
@@ -244,34 +1040,531 @@ fn scatter_ray(ray_in: Ray, hit: Intersection) -> Scatter {
   }
   let material = materials[hit.material_index];
   if (material.type_mat == MATERIAL_LAMBERTIAN) {
-    let rand_dir = sample_sphere();
-    let scattered_dir = select(-rand_dir, rand_dir, dot(rand_dir, normal) > 0.);
+    let scattered_dir = orthonormal_basis(normal) * sample_cosine_hemisphere();
     let scattered_ray = Ray(hit_point + normal * epsilon, scattered_dir);
     let cos_theta = max(0.0, dot(normal, scattered_dir));
-    let albedo = material.colour;
-    return Scatter(scattered_ray, hit, albedo);
-  } else if(material.type_mat == MATERIAL_METAL){
+    // Weight the sample by BRDF * cos / pdf. With cosine-weighted sampling this is simply the
+    // albedo, unless rounding puts the direction below the surface.
+    let pdf = lambertian_pdf(cos_theta);
+    let weight = select(vec3(0.), lambertian_brdf(material) * cos_theta / pdf, pdf > 0.);
+    return Scatter(scattered_ray, hit, weight, pdf, false);
+  } else if(material.type_mat == MATERIAL_METAL && material.roughness == 0.){
     let reflected = reflect(normalize(ray_in.direction), normal);
     let scattered_ray = Ray(hit_point + normal * epsilon, normalize(reflected));
     let albedo = material.colour ;
-    return Scatter(scattered_ray, hit, albedo);
+    return Scatter(scattered_ray, hit, albedo, 0., true);
+  } else if(material.type_mat == MATERIAL_METAL){
+    // Reflect about a microfacet normal drawn from the distribution of visible normals. The
+    // D term cancels out of BRDF * cos / pdf, leaving the Fresnel term (the metal's colour) and
+    // the ratio of the two-way to the one-way masking term.
+    let alpha = ggx_alpha(material);
+    let basis = orthonormal_basis(normal);
+    let wo = -incident;
+    let wo_local = wo * basis;
+    let h = basis * sample_ggx_vndf(wo_local, alpha);
+    let scattered_dir = reflect(incident, h);
+    let scattered_ray = Ray(hit_point + normal * epsilon, scattered_dir);
+    let wi_local = scattered_dir * basis;
+    if wo_local.z <= 0. || wi_local.z <= 0. {
+      return Scatter(scattered_ray, hit, vec3(0.), 0., false);
+    }
+    let weight = material.colour * ggx_g2(wo_local, wi_local, alpha) / ggx_g1(wo_local, alpha);
+    let pdf = ggx_pdf(wo_local, wi_local, alpha);
+    return Scatter(scattered_ray, hit, weight, pdf, false);
+  } else if(material.type_mat == MATERIAL_PRINCIPLED){
+    let is_front_face = dot(hit.normal, incident) < 0;
+    let basis = orthonormal_basis(normal);
+    let eta = principled_eta(material, is_front_face);
+    let sample = sample_principled(material, -incident * basis, eta);
+    // Transmitted rays continue on the other side of the surface.
+    let side = select(-normal, normal, sample.direction.z > 0.);
+    let scattered_ray = Ray(hit_point + side * epsilon, basis * sample.direction);
+    return Scatter(scattered_ray, hit, sample.weight, sample.pdf, false);
   }
   else {
+    // Choose between reflection and refraction with probability given by the Fresnel
+    // reflectance. Since the choice is made in proportion to each lobe's contribution, the
+    // throughput is not changed. Absorption inside the medium is accounted for when the ray leaves
+    // it, see `path_tracer_cs`.
     let is_front_face = dot(hit.normal, incident) < 0;
-    let ior = select(1/material.refractive_index, material.refractive_index, is_front_face);
-    let k = dot(normal,incident);
-    let sin_theta = ior * sqrt(1 - k*k);
-    let albedo = material.colour;
-    if(sin_theta<=1){
-      let refracted_ray = refract(incident,normalize(normal),ior);
-      let scattered_ray = Ray(hit_point + refracted_ray * epsilon, normalize(refracted_ray));
-      return Scatter(scattered_ray, hit, albedo);
+    // The ratio of the refractive indices on the incident and the transmitted side.
+    let eta = select(material.refractive_index, 1. / material.refractive_index, is_front_face);
+    let cos_i = min(dot(-incident, normal), 1.);
+    let sin2_t = eta * eta * (1. - cos_i * cos_i);
+    var reflectance = 1.;
+    if sin2_t < 1. {
+      // Schlick's approximation is only accurate with the cosine on the side of the less dense
+      // medium.
+      let cos_t = sqrt(1. - sin2_t);
+      reflectance = schlick_reflectance(select(cos_i, cos_t, eta > 1.), eta);
+    }
+    if rand_f32() >= reflectance {
+      let refracted = refract(incident, normal, eta);
+      let scattered_ray = Ray(hit_point - normal * epsilon, normalize(refracted));
+      return Scatter(scattered_ray, hit, vec3(1.), 0., true);
+    }
+    let reflected = reflect(incident, normal);
+    let scattered_ray = Ray(hit_point + normal * epsilon, normalize(reflected));
+    return Scatter(scattered_ray, hit, vec3(1.), 0., true);
+  }
+  return Scatter(Ray(vec3(0.), vec3(0.)), hit, vec3(0.), 0., true);
+}
+// Schlick's approximation of the Fresnel reflectance of a dielectric interface, where `eta` is
+// the ratio of the refractive indices on either side.
+fn schlick_reflectance(cosine: f32, eta: f32) -> f32 {
+  var r0 = (1. - eta) / (1. + eta);
+  r0 = r0 * r0;
+  return r0 + (1. - r0) * pow(1. - cosine, 5.);
+}
+
+// Whether a material scatters light into a single direction, which light sampling can never find.
+fn is_specular(material: Material) -> bool {
+  return material.type_mat == MATERIAL_GLASS
+      || (material.type_mat == MATERIAL_METAL && material.roughness == 0.);
+}
+
+// The BSDF of a non-specular material for light arriving from `wi` and leaving towards `wo`.
+// `normal` faces `wo`, and `front_face` tells whether it is the outward normal of the surface.
+fn bsdf_eval(material: Material, normal: vec3f, wo: vec3f, wi: vec3f, front_face: bool) -> vec3f {
+  if material.type_mat == MATERIAL_PRINCIPLED {
+    let basis = orthonormal_basis(normal);
+    let eta = principled_eta(material, front_face);
+    return principled_eval(material, wo * basis, wi * basis, eta);
+  }
+  if material.type_mat == MATERIAL_METAL {
+    let basis = orthonormal_basis(normal);
+    let wo_local = wo * basis;
+    let wi_local = wi * basis;
+    if wo_local.z <= 0. || wi_local.z <= 0. {
+      return vec3(0.);
     }
-    else {
-      let reflected = reflect(incident, normal);
-      let scattered_ray = Ray(hit_point + normal * epsilon, normalize(reflected));
-      return Scatter(scattered_ray, hit, albedo);
+    let alpha = ggx_alpha(material);
+    let h = normalize(wo_local + wi_local);
+    return material.colour * ggx_d(h, alpha) * ggx_g2(wo_local, wi_local, alpha)
+        / (4. * wo_local.z * wi_local.z);
+  }
+  return lambertian_brdf(material);
+}
+
+// The solid angle density with which `scatter_ray` samples `wi` at a non-specular material.
+fn bsdf_pdf(material: Material, normal: vec3f, wo: vec3f, wi: vec3f, front_face: bool) -> f32 {
+  if material.type_mat == MATERIAL_PRINCIPLED {
+    let basis = orthonormal_basis(normal);
+    let eta = principled_eta(material, front_face);
+    return principled_pdf(material, wo * basis, wi * basis, eta);
+  }
+  if material.type_mat == MATERIAL_METAL {
+    let basis = orthonormal_basis(normal);
+    let wo_local = wo * basis;
+    let wi_local = wi * basis;
+    if wo_local.z <= 0. || wi_local.z <= 0. {
+      return 0.;
     }
+    return ggx_pdf(wo_local, wi_local, ggx_alpha(material));
   }
-  return Scatter(Ray(vec3(0.), vec3(0.)), hit, vec3(0.));
-}
\ No newline at end of file
+  return lambertian_pdf(dot(normal, wi));
+}
+
+// The GGX alpha parameter, the square of the artist-friendly roughness.
+fn ggx_alpha(material: Material) -> f32 {
+  return max(material.roughness * material.roughness, MIN_GGX_ALPHA);
+}
+
+// The GGX (Trowbridge-Reitz) distribution of microfacet normals, for a normal `h` in the local
+// frame of the surface. Written in terms of the tangential components of `h`, which are more
+// accurate than 1 - h.z^2 close to the peak of narrow distributions.
+fn ggx_d(h: vec3f, alpha: f32) -> f32 {
+  let a2 = alpha * alpha;
+  let d = (h.x * h.x + h.y * h.y) / a2 + h.z * h.z;
+  return 1. / (PI * a2 * d * d);
+}
+
+// Smith's auxiliary function for GGX, for a direction `w` in the local frame.
+fn ggx_lambda(w: vec3f, alpha: f32) -> f32 {
+  let tan2_theta = max(1. - w.z * w.z, 0.) / (w.z * w.z);
+  return 0.5 * (sqrt(1. + alpha * alpha * tan2_theta) - 1.);
+}
+
+// The fraction of microfacets visible from `w`.
+fn ggx_g1(w: vec3f, alpha: f32) -> f32 {
+  return 1. / (1. + ggx_lambda(w, alpha));
+}
+
+// The height-correlated fraction of microfacets visible from both `wo` and `wi`.
+fn ggx_g2(wo: vec3f, wi: vec3f, alpha: f32) -> f32 {
+  return 1. / (1. + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha));
+}
+
+// The density of reflecting `wo` into `wi` about a visible normal, with respect to solid angle.
+// This is D_wo(h) times the Jacobian of the reflection, 1 / (4 wo.h).
+fn ggx_pdf(wo: vec3f, wi: vec3f, alpha: f32) -> f32 {
+  let h = normalize(wo + wi);
+  return ggx_g1(wo, alpha) * ggx_d(h, alpha) / (4. * wo.z);
+}
+
+// Samples a microfacet normal visible from `wo`, in the local frame. See Heitz, "Sampling the GGX
+// Distribution of Visible Normals", JCGT 2018.
+fn sample_ggx_vndf(wo: vec3f, alpha: f32) -> vec3f {
+  // Stretch the view direction to the configuration where the microsurface is a hemisphere.
+  let v = normalize(vec3(alpha * wo.x, alpha * wo.y, wo.z));
+  let len2 = v.x * v.x + v.y * v.y;
+  let t1 = select(vec3(1., 0., 0.), vec3(-v.y, v.x, 0.) * inverseSqrt(len2), len2 > 0.);
+  let t2 = cross(v, t1);
+
+  // Sample the projected area of the hemisphere as seen from `v`.
+  let r = sqrt(rand_f32());
+  let phi = 2. * PI * rand_f32();
+  let p1 = r * cos(phi);
+  let s = 0.5 * (1. + v.z);
+  let p2 = (1. - s) * sqrt(max(1. - p1 * p1, 0.)) + s * r * sin(phi);
+  let n = p1 * t1 + p2 * t2 + sqrt(max(1. - p1 * p1 - p2 * p2, 0.)) * v;
+
+  // Unstretch back to the original microsurface.
+  return normalize(vec3(alpha * n.x, alpha * n.y, max(n.z, 0.)));
+}
+
+// Fresnel reflectance of a conductor or dielectric with reflectance `f0` at normal incidence,
+// using Schlick's approximation.
+fn schlick_fresnel(f0: vec3f, cosine: f32) -> vec3f {
+  return f0 + (1. - f0) * pow(1. - saturate(cosine), 5.);
+}
+
+// Exact Fresnel reflectance of unpolarized light at a dielectric interface, where `eta` is the
+// refractive index on the transmitted side relative to the incident side.
+fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+  let sin2_t = (1. - cos_i * cos_i) / (eta * eta);
+  if sin2_t >= 1. {
+    return 1.;
+  }
+  let cos_t = sqrt(1. - sin2_t);
+  let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+  let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+  return 0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular);
+}
+
+// The refractive index below the surface of a principled material relative to the one above it,
+// where above is the side the light leaves towards.
+fn principled_eta(material: Material, front_face: bool) -> f32 {
+  return select(1. / material.refractive_index, material.refractive_index, front_face);
+}
+
+// Specular reflectance of the opaque part of a principled material.
+fn principled_specular_fresnel(material: Material, cosine: f32) -> vec3f {
+  let dielectric = (1. - material.metallic) * (1. - material.transmission);
+  return dielectric * schlick_fresnel(vec3(0.08 * material.specular), cosine)
+      + material.metallic * schlick_fresnel(material.colour, cosine);
+}
+
+// The probabilities with which `sample_principled` picks the diffuse, specular, transmission and
+// clear coat lobes, roughly in proportion to how much light each of them reflects towards `wo`.
+fn principled_lobe_probabilities(material: Material, wo: vec3f) -> vec4f {
+  let lobes = vec4(
+    (1. - material.metallic) * (1. - material.transmission) * luminance(material.colour),
+    luminance(principled_specular_fresnel(material, wo.z)),
+    (1. - material.metallic) * material.transmission,
+    0.25 * material.clearcoat * schlick_fresnel(vec3(0.04), wo.z).x,
+  );
+  let total = dot(lobes, vec4(1.));
+  return select(vec4(0.), lobes / total, total > 0.);
+}
+
+// The microfacet normal that refracts `wo` into `wi`, or zero if there is none. See Walter et al.,
+// "Microfacet Models for Refraction through Rough Surfaces", EGSR 2007.
+fn refraction_half_vector(wo: vec3f, wi: vec3f, eta: f32) -> vec3f {
+  let h = wo + wi * eta;
+  if dot(h, h) == 0. {
+    return vec3(0.);
+  }
+  let n = normalize(h) * sign(h.z);
+  if dot(wo, n) <= 0. || dot(wi, n) >= 0. {
+    return vec3(0.);
+  }
+  return n;
+}
+
+// The principled BSDF for light arriving from `wi` and leaving towards `wo`, both in the local
+// frame of the surface with `wo` above it. `eta` is the relative refractive index, see
+// `principled_eta`. Transmitted radiance is scaled by 1 / eta^2.
+fn principled_eval(material: Material, wo: vec3f, wi: vec3f, eta: f32) -> vec3f {
+  let alpha = ggx_alpha(material);
+  let dielectric = 1. - material.metallic;
+  if wo.z <= 0. || wi.z == 0. {
+    return vec3(0.);
+  }
+  if wi.z < 0. {
+    let h = refraction_half_vector(wo, wi, eta);
+    if all(h == vec3(0.)) {
+      return vec3(0.);
+    }
+    let wo_h = dot(wo, h);
+    let wi_h = dot(wi, h);
+    let denominator = wi_h + wo_h / eta;
+    let transmittance = 1. - fresnel_dielectric(wo_h, eta);
+    let microfacets = ggx_d(h, alpha) * ggx_g2(wo, wi, alpha)
+        * abs(wi_h * wo_h / (wi.z * wo.z * denominator * denominator));
+    return dielectric * material.transmission * material.colour * transmittance * microfacets
+        / (eta * eta);
+  }
+
+  let h = normalize(wo + wi);
+  let wo_h = dot(wo, h);
+  let specular = ggx_d(h, alpha) * ggx_g2(wo, wi, alpha) / (4. * wo.z * wi.z);
+  let clearcoat = ggx_d(h, CLEARCOAT_ALPHA) * ggx_g2(wo, wi, CLEARCOAT_ALPHA) / (4. * wo.z * wi.z);
+  return dielectric * (1. - material.transmission) * material.colour / PI
+      + principled_specular_fresnel(material, wo_h) * specular
+      + dielectric * material.transmission * fresnel_dielectric(wo_h, eta) * specular
+      + 0.25 * material.clearcoat * schlick_fresnel(vec3(0.04), wo_h) * clearcoat;
+}
+
+// The solid angle density with which `sample_principled` samples `wi`.
+fn principled_pdf(material: Material, wo: vec3f, wi: vec3f, eta: f32) -> f32 {
+  let alpha = ggx_alpha(material);
+  let probabilities = principled_lobe_probabilities(material, wo);
+  if wo.z <= 0. || wi.z == 0. {
+    return 0.;
+  }
+  if wi.z < 0. {
+    let h = refraction_half_vector(wo, wi, eta);
+    if all(h == vec3(0.)) {
+      return 0.;
+    }
+    // The density of the visible normal times the Jacobian of refraction.
+    let wo_h = dot(wo, h);
+    let wi_h = dot(wi, h);
+    let denominator = wi_h + wo_h / eta;
+    let visible_normal_pdf = ggx_g1(wo, alpha) * ggx_d(h, alpha) * wo_h / wo.z;
+    let transmittance = 1. - fresnel_dielectric(wo_h, eta);
+    return probabilities.z * transmittance * visible_normal_pdf * abs(wi_h)
+        / (denominator * denominator);
+  }
+
+  let h = normalize(wo + wi);
+  return probabilities.x * lambertian_pdf(wi.z)
+      + probabilities.y * ggx_pdf(wo, wi, alpha)
+      + probabilities.z * fresnel_dielectric(dot(wo, h), eta) * ggx_pdf(wo, wi, alpha)
+      + probabilities.w * ggx_pdf(wo, wi, CLEARCOAT_ALPHA);
+}
+
+// Samples the principled BSDF by picking one of its lobes and weighting the direction with the
+// combined density of all of them.
+fn sample_principled(material: Material, wo: vec3f, eta: f32) -> BsdfSample {
+  let alpha = ggx_alpha(material);
+  let probabilities = principled_lobe_probabilities(material, wo);
+  let u = rand_f32();
+  var wi: vec3f;
+  var transmitted = false;
+  if u < probabilities.x {
+    wi = sample_cosine_hemisphere();
+  } else if u < probabilities.x + probabilities.y {
+    wi = reflect(-wo, sample_ggx_vndf(wo, alpha));
+  } else if u < probabilities.x + probabilities.y + probabilities.z {
+    // Reflect or refract about the microfacet in proportion to its Fresnel reflectance.
+    let h = sample_ggx_vndf(wo, alpha);
+    if rand_f32() < fresnel_dielectric(dot(wo, h), eta) {
+      wi = reflect(-wo, h);
+    } else {
+      wi = refract(-wo, h, 1. / eta);
+      transmitted = true;
+    }
+  } else {
+    wi = reflect(-wo, sample_ggx_vndf(wo, CLEARCOAT_ALPHA));
+  }
+
+  // Directions that end up on the wrong side of the surface are discarded, so that the density
+  // of the remaining ones is exactly `principled_pdf`.
+  let pdf = principled_pdf(material, wo, wi, eta);
+  if select(wi.z <= 0., wi.z >= 0., transmitted) || pdf <= 0. {
+    return BsdfSample(wi, vec3(0.), 0.);
+  }
+  let weight = principled_eval(material, wo, wi, eta) * abs(wi.z) / pdf;
+  return BsdfSample(wi, weight, pdf);
+}
+
+fn lambertian_brdf(material: Material) -> vec3f {
+  return material.colour / PI;
+}
+
+// The solid angle density with which `scatter_ray` samples a direction that makes an angle with
+// the normal whose cosine is `cos_theta`.
+fn lambertian_pdf(cos_theta: f32) -> f32 {
+  return max(cos_theta, 0.) / PI;
+}
+
+// Balance between two sampling strategies with the power heuristic (beta = 2), returning the
+// weight of the strategy with density `pdf`.
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+  let a = pdf * pdf;
+  let b = other_pdf * other_pdf;
+  return select(a / (a + b), 0., a + b == 0.);
+}
+
+fn luminance(c: vec3f) -> f32 {
+  return dot(c, vec3(0.2126, 0.7152, 0.0722));
+}
+
+fn primitive_material(id: u32) -> Material {
+  if (id & PRIMITIVE_TRIANGLE_BIT) != 0u {
+    return materials[triangles[id & ~PRIMITIVE_TRIANGLE_BIT].material];
+  }
+  return materials[spheres[id].material];
+}
+
+fn primitive_area(id: u32) -> f32 {
+  if (id & PRIMITIVE_TRIANGLE_BIT) != 0u {
+    let triangle = triangles[id & ~PRIMITIVE_TRIANGLE_BIT];
+    let p0 = vertices[triangle.indices.x].position;
+    let p1 = vertices[triangle.indices.y].position;
+    let p2 = vertices[triangle.indices.z].position;
+    return 0.5 * length(cross(p1 - p0, p2 - p0));
+  }
+  let radius = spheres[id].radius;
+  return 4. * PI * radius * radius;
+}
+
+// The power of a light, which is proportional to the probability of picking it. This must match
+// the computation of the light CDF in scene.rs.
+fn light_power(id: u32) -> f32 {
+  return primitive_area(id) * luminance(primitive_material(id).emission);
+}
+
+fn total_light_power() -> f32 {
+  return lights[arrayLength(&lights) - 1u].cdf;
+}
+
+// Converts a density with respect to the area of a light to a density with respect to solid angle
+// at a point `distance` away, where the direction to the light makes an angle with the light's
+// normal whose cosine is `cos_light`. Lights emit from both sides.
+fn area_to_solid_angle_pdf(pdf_area: f32, distance: f32, cos_light: f32) -> f32 {
+  return pdf_area * distance * distance / max(abs(cos_light), 1e-6);
+}
+
+// The density with which `sample_light` picks the point where `ray` hits the light `id` at
+// distance `t`, with respect to solid angle. `ray.direction` must be normalized.
+fn light_pdf(id: u32, ray: Ray, t: f32) -> f32 {
+  let total_power = total_light_power();
+  if total_power <= 0. {
+    return 0.;
+  }
+  let position = point_on_ray(ray, t);
+  var normal: vec3f;
+  if (id & PRIMITIVE_TRIANGLE_BIT) != 0u {
+    let triangle = triangles[id & ~PRIMITIVE_TRIANGLE_BIT];
+    let p0 = vertices[triangle.indices.x].position;
+    normal = normalize(cross(vertices[triangle.indices.y].position - p0,
+                             vertices[triangle.indices.z].position - p0));
+  } else {
+    normal = normalize(position - spheres[id].center);
+  }
+  let pdf_area = light_power(id) / total_power / primitive_area(id);
+  return area_to_solid_angle_pdf(pdf_area, t, dot(normal, ray.direction));
+}
+
+// Picks a light with probability proportional to its power and a uniformly distributed point on
+// its surface.
+fn sample_light(shading_point: vec3f) -> LightSample {
+  let total_power = total_light_power();
+  let target_power = rand_f32() * total_power;
+  var lo = 0u;
+  var hi = arrayLength(&lights) - 1u;
+  while lo < hi {
+    let mid = (lo + hi) / 2u;
+    if lights[mid].cdf <= target_power {
+      lo = mid + 1u;
+    } else {
+      hi = mid;
+    }
+  }
+  let id = lights[lo].primitive;
+
+  var position: vec3f;
+  var normal: vec3f;
+  if (id & PRIMITIVE_TRIANGLE_BIT) != 0u {
+    let triangle = triangles[id & ~PRIMITIVE_TRIANGLE_BIT];
+    let p0 = vertices[triangle.indices.x].position;
+    let p1 = vertices[triangle.indices.y].position;
+    let p2 = vertices[triangle.indices.z].position;
+    let su = sqrt(rand_f32());
+    let b1 = su * (1. - rand_f32());
+    let b2 = su - b1;
+    position = p0 + b1 * (p1 - p0) + b2 * (p2 - p0);
+    normal = normalize(cross(p1 - p0, p2 - p0));
+  } else {
+    let sphere = spheres[id];
+    normal = sample_sphere();
+    position = sphere.center + sphere.radius * normal;
+  }
+
+  let to_light = position - shading_point;
+  let distance = length(to_light);
+  let pdf_area = light_power(id) / total_power / primitive_area(id);
+  let pdf = area_to_solid_angle_pdf(pdf_area, distance, dot(normal, to_light / distance));
+  return LightSample(position, normal, primitive_material(id).emission, pdf);
+}
+
+// Estimates the light arriving directly from emissive primitives and the environment map or the
+// sun at a non-specular surface and leaving towards `wo`, by sampling a point on a light and a
+// direction towards the environment or the sun and tracing shadow rays to them. Each estimate is
+// weighted against BSDF sampling, which can find the same light, with the power heuristic.
+fn sample_direct_light(
+  hit_point: vec3f,
+  normal: vec3f,
+  wo: vec3f,
+  material: Material,
+  front_face: bool,
+) -> vec3f {
+  var radiance = vec3(0.);
+  if total_light_power() > 0. {
+    let light = sample_light(hit_point);
+    let to_light = light.position - hit_point;
+    let distance = length(to_light);
+    radiance += direct_light(
+        hit_point, normal, wo, material, front_face, to_light / distance, distance,
+        light.emission, light.pdf);
+  }
+  if uniforms.environment != 0u {
+    let sample = sample_environment();
+    radiance += direct_light(
+        hit_point, normal, wo, material, front_face, sample.direction, FLT_MAX, sample.radiance,
+        sample.pdf);
+  }
+  if uniforms.physical_sky != 0u && any(uniforms.sky.sun_radiance > vec3(0.)) {
+    let sample = sample_sun();
+    radiance += direct_light(
+        hit_point, normal, wo, material, front_face, sample.direction, FLT_MAX, sample.radiance,
+        sample.pdf);
+  }
+  return radiance;
+}
+
+// The contribution of `emission` arriving from `direction`, sampled with solid angle density
+// `pdf`, unless something closer than `distance` casts a shadow. See `sample_direct_light`.
+fn direct_light(
+  hit_point: vec3f,
+  normal: vec3f,
+  wo: vec3f,
+  material: Material,
+  front_face: bool,
+  direction: vec3f,
+  distance: f32,
+  emission: vec3f,
+  pdf: f32,
+) -> vec3f {
+  let cos_theta = dot(normal, direction);
+  // Only principled materials transmit light arriving from below the surface.
+  let transmits = material.type_mat == MATERIAL_PRINCIPLED && material.transmission > 0.;
+  if (cos_theta <= 0. && !transmits) || cos_theta == 0. || pdf <= 0. {
+    return vec3(0.);
+  }
+
+  // The shadow ray is expected to hit the light itself at `distance`.
+  let epsilon = 0.001;
+  let side = select(-normal, normal, cos_theta > 0.);
+  let shadow = closest_hit(Ray(hit_point + side * epsilon, direction));
+  if is_valid_intersection(shadow) && shadow.t < distance * (1. - epsilon) {
+    return vec3(0.);
+  }
+
+  let weight = power_heuristic(pdf, bsdf_pdf(material, normal, wo, direction, front_face));
+  let brdf = bsdf_eval(material, normal, wo, direction, front_face);
+  return brdf * emission * abs(cos_theta) * weight / pdf;
+}
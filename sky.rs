@@ -0,0 +1,225 @@
+//! An analytic model of the clear daylight sky and the sun, after Preetham et al., "A Practical
+//! Analytic Model for Daylight", SIGGRAPH 1999.
+
+use {
+    crate::math::Vec4,
+    bytemuck::{Pod, Zeroable},
+    std::f32::consts::PI,
+};
+
+// Angular radius of the sun seen from the earth, 0.2667 degrees.
+const SUN_ANGULAR_RADIUS: f32 = 0.004654;
+// Luminance of the sun outside the atmosphere in kcd/m^2, the unit of the sky model.
+const SUN_LUMINANCE: f32 = 1.6e6;
+// Factor that luminances of the model are scaled by to get radiance, so that the sky with the sun
+// high up is about as bright as the default gradient.
+const LUMINANCE_SCALE: f32 = 0.1;
+// Elevation in degrees below which the sky is black. Between it and the horizon the sky fades out,
+// since the model does not cover twilight.
+const TWILIGHT_ELEVATION: f32 = -6.;
+// Wavelengths in micrometres at which the transmittance of the atmosphere is evaluated for the
+// red, green and blue components of the sun.
+const WAVELENGTHS: [f32; 3] = [0.680, 0.550, 0.440];
+// Rotation of the earth in radians per hour.
+const HOUR_ANGLE: f32 = 2. * PI / 24.;
+
+/// GPU representation of the sky, laid out to match `struct SkyUniforms` in shaders.wgsl.
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+#[repr(C)]
+pub struct SkyUniforms {
+    // The coefficients A to E of the Perez distribution of the luminance Y and the chromaticity
+    // coordinates x and y, in the first three components.
+    perez: [[f32; 4]; 5],
+    // Y, x and y at the zenith, divided by the Perez function at the zenith so that it only needs
+    // to be multiplied by its value at another point.
+    zenith: [f32; 4],
+    // Unit vector towards the sun, no lower than the horizon.
+    sun_direction: [f32; 3],
+    cos_sun_radius: f32,
+    // Radiance of the disk of the sun after passing through the atmosphere.
+    sun_radiance: [f32; 3],
+    _padding: u32,
+}
+
+/// A clear sky whose colour depends on the position of the sun and the haziness of the air. The
+/// sun moves along the arc that it follows through the day at a given latitude, at the equinox if
+/// it starts on the celestial equator and at another time of the year otherwise.
+#[derive(Debug, Copy, Clone)]
+pub struct Sky {
+    uniforms: SkyUniforms,
+    turbidity: f32,
+    // Unit vector towards the sun, which can be below the horizon.
+    sun: Vec4,
+    // Unit vector towards the celestial north pole, which the sun turns around.
+    pole: Vec4,
+}
+
+impl Sky {
+    /// The sky with the sun at `elevation` degrees above the horizon and `azimuth` degrees east of
+    /// north, which is towards -z with east towards +x. `turbidity` is the haziness of the air,
+    /// from 2 for a very clear sky to 10 for a hazy one, and `latitude` is in degrees north.
+    pub fn new(turbidity: f32, elevation: f32, azimuth: f32, latitude: f32) -> Sky {
+        let latitude = latitude.to_radians();
+        let mut sky = Sky {
+            uniforms: SkyUniforms::zeroed(),
+            turbidity,
+            sun: direction(elevation.to_radians(), azimuth.to_radians()),
+            pole: Vec4::new(0., latitude.sin(), -latitude.cos()),
+        };
+        sky.update_uniforms();
+        sky
+    }
+
+    pub fn uniforms(&self) -> &SkyUniforms {
+        &self.uniforms
+    }
+
+    pub fn turbidity(&self) -> f32 {
+        self.turbidity
+    }
+
+    /// The elevation of the sun above the horizon in degrees.
+    pub fn sun_elevation(&self) -> f32 {
+        self.sun.y().clamp(-1., 1.).asin().to_degrees()
+    }
+
+    /// The azimuth of the sun in degrees east of north, between 0 and 360.
+    pub fn sun_azimuth(&self) -> f32 {
+        self.sun.x().atan2(-self.sun.z()).to_degrees().rem_euclid(360.)
+    }
+
+    /// Moves the sun along its daily arc by `hours`, or back if negative.
+    pub fn advance(&mut self, hours: f32) {
+        // Rodrigues' rotation around the pole, clockwise seen from above it so that the sun rises
+        // in the east and sets in the west.
+        let (sin, cos) = (-hours * HOUR_ANGLE).sin_cos();
+        let (pole, sun) = (self.pole, self.sun);
+        let rotated = sun * cos + pole.cross(&sun) * sin + pole * pole.dot(&sun) * (1. - cos);
+        self.sun = rotated.normalized();
+        self.update_uniforms();
+    }
+
+    /// Same as `physical_sky_radiance` in shaders.wgsl: the RGB radiance of the sky towards the
+    /// unit vector `direction`, without the sun.
+    pub fn radiance(&self, direction: Vec4) -> [f32; 3] {
+        let u = &self.uniforms;
+        let sun = Vec4::from(u.sun_direction);
+        let cos_gamma = direction.dot(&sun).clamp(-1., 1.);
+        let yxy: [f32; 3] = std::array::from_fn(|i| {
+            u.zenith[i] * perez(direction.y(), cos_gamma, u.perez.map(|c| c[i]))
+        });
+        xyy_to_rgb(yxy).map(|c| c.max(0.))
+    }
+
+    // Evaluates the model for the current position of the sun.
+    fn update_uniforms(&mut self) {
+        let t = self.turbidity;
+        let elevation = self.sun.y().clamp(-1., 1.).asin();
+        // The model is evaluated with the sun no lower than the horizon, and faded out below it.
+        let sun = if elevation >= 0. {
+            self.sun
+        } else {
+            Vec4::new(self.sun.x(), 0., self.sun.z()).normalized()
+        };
+        let theta_s = PI / 2. - elevation.max(0.);
+        let twilight = smoothstep(TWILIGHT_ELEVATION.to_radians(), 0., elevation);
+
+        let perez_coefficients = [
+            [0.1787 * t - 1.4630, -0.0193 * t - 0.2592, -0.0167 * t - 0.2608],
+            [-0.3554 * t + 0.4275, -0.0665 * t + 0.0008, -0.0950 * t + 0.0092],
+            [-0.0227 * t + 5.3251, -0.0004 * t + 0.2125, -0.0079 * t + 0.2102],
+            [0.1206 * t - 2.5771, -0.0641 * t - 0.8989, -0.0441 * t - 1.6537],
+            [-0.0670 * t + 0.3703, -0.0033 * t + 0.0452, -0.0109 * t + 0.0529],
+        ];
+
+        // Luminance in kcd/m^2 and chromaticity at the zenith.
+        let chi = (4. / 9. - t / 120.) * (PI - 2. * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let chromaticity = |m: [[f32; 4]; 3]| {
+            let powers = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.];
+            let row = |r: [f32; 4]| (0..4).map(|i| r[i] * powers[i]).sum::<f32>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+        let zenith = [zenith_luminance * LUMINANCE_SCALE * twilight, zenith_x, zenith_y];
+
+        // Perez function at the zenith, where the angle from the sun is its angle from the zenith.
+        let mut normalized_zenith = [0.; 4];
+        for i in 0..3 {
+            let coefficients = perez_coefficients.map(|c| c[i]);
+            normalized_zenith[i] = zenith[i] / perez(1., theta_s.cos(), coefficients);
+        }
+
+        let cos_sun_radius = SUN_ANGULAR_RADIUS.cos();
+        self.uniforms = SkyUniforms {
+            perez: perez_coefficients.map(|[luminance, x, y]| [luminance, x, y, 0.]),
+            zenith: normalized_zenith,
+            sun_direction: sun.to_array(),
+            cos_sun_radius,
+            sun_radiance: if elevation >= 0. {
+                sun_transmittance(t, theta_s).map(|c| c * SUN_LUMINANCE * LUMINANCE_SCALE)
+            } else {
+                [0.; 3]
+            },
+            _padding: 0,
+        };
+    }
+}
+
+// The unit vector at `elevation` above the horizon and `azimuth` east of north, in radians.
+fn direction(elevation: f32, azimuth: f32) -> Vec4 {
+    let (sin_elevation, cos_elevation) = elevation.sin_cos();
+    let (sin_azimuth, cos_azimuth) = azimuth.sin_cos();
+    Vec4::new(cos_elevation * sin_azimuth, sin_elevation, -cos_elevation * cos_azimuth)
+}
+
+// The Perez sky distribution for a direction whose angle from the zenith has the cosine
+// `cos_theta` and whose angle from the sun has the cosine `cos_gamma`. Directions below the
+// horizon get the value at the horizon.
+fn perez(cos_theta: f32, cos_gamma: f32, [a, b, c, d, e]: [f32; 5]) -> f32 {
+    let gamma = cos_gamma.clamp(-1., 1.).acos();
+    let gradation = 1. + a * (b / cos_theta.max(1e-3)).exp();
+    gradation * (1. + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+// Converts luminance Y and chromaticity x, y to linear sRGB.
+fn xyy_to_rgb([luminance, x, y]: [f32; 3]) -> [f32; 3] {
+    if y <= 0. {
+        return [0.; 3];
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1. - x - y) / y * luminance;
+    [
+        3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+        -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+        0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+    ]
+}
+
+// The fraction of sunlight at `WAVELENGTHS` that reaches the ground through Rayleigh scattering by
+// air and scattering by aerosols, with the sun at `theta_s` radians from the zenith. See appendix
+// A.2 of Preetham et al.
+fn sun_transmittance(turbidity: f32, theta_s: f32) -> [f32; 3] {
+    // Relative optical mass of the air, which is finite at the horizon.
+    let air_mass = 1. / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    WAVELENGTHS.map(|lambda| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosols = beta * lambda.powf(-1.3);
+        (-(rayleigh + aerosols) * air_mass).exp()
+    })
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
@@ -0,0 +1,38 @@
+//! Helpers shared by the unit tests.
+
+use crate::{math::Vec4, sampling::Rng};
+
+/// Returns a uniformly distributed point in the cube from -`extent` to `extent` on each axis.
+pub fn random_point(rng: &mut Rng, extent: f32) -> Vec4 {
+    Vec4::new(
+        rng.range(-extent, extent),
+        rng.range(-extent, extent),
+        rng.range(-extent, extent),
+    )
+}
+
+/// Monte Carlo estimate of the expected value of `f`, returned with its standard error.
+pub fn estimate(samples: u32, mut f: impl FnMut() -> f32) -> (f32, f32) {
+    let (mut sum, mut sum_squared) = (0f64, 0f64);
+    for _ in 0..samples {
+        let x = f() as f64;
+        sum += x;
+        sum_squared += x * x;
+    }
+    let n = samples as f64;
+    let mean = sum / n;
+    let variance = (sum_squared / n - mean * mean).max(0.);
+    (mean as f32, (variance / n).sqrt() as f32)
+}
+
+/// Asserts that an estimate is no further from the expected value than its noise can explain.
+pub fn assert_estimate(name: &str, (mean, standard_error): (f32, f32), expected: f32) {
+    assert!(
+        (mean - expected).abs() <= 4. * standard_error + 1e-3,
+        "{}: expected {} but estimated {} (+- {})",
+        name,
+        expected,
+        mean,
+        standard_error
+    );
+}
@@ -0,0 +1,149 @@
+//! Tone mapping operators that compress radiance into the range of a display, and the CPU versions
+//! of the display transform in shaders.wgsl used for images written without a window.
+
+use {
+    anyhow::{bail, Error},
+    std::{fmt, str::FromStr},
+};
+
+/// A tone mapping operator. The values are those of the `TONE_MAPPING_*` constants in
+/// shaders.wgsl.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ToneMapping {
+    /// No compression, values above 1 are clipped by the display.
+    Linear = 0,
+    Reinhard = 1,
+    /// Reinhard on luminance, with white reached at `EXTENDED_REINHARD_WHITE`.
+    ExtendedReinhard = 2,
+    /// Krzysztof Narkowicz's fit of the ACES filmic curve.
+    Aces = 3,
+    /// Troy Sobotka's AgX, using Benjamin Wrensch's polynomial approximation.
+    Agx = 4,
+}
+
+const ALL: [ToneMapping; 5] = [
+    ToneMapping::Linear,
+    ToneMapping::Reinhard,
+    ToneMapping::ExtendedReinhard,
+    ToneMapping::Aces,
+    ToneMapping::Agx,
+];
+
+// Same as the constant in shaders.wgsl.
+const EXTENDED_REINHARD_WHITE: f32 = 4.;
+
+impl ToneMapping {
+    /// The operator after this one, wrapping around to the first.
+    pub fn next(self) -> ToneMapping {
+        ALL[(self as usize + 1) % ALL.len()]
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ToneMapping::Linear => "linear",
+            ToneMapping::Reinhard => "reinhard",
+            ToneMapping::ExtendedReinhard => "extended-reinhard",
+            ToneMapping::Aces => "aces",
+            ToneMapping::Agx => "agx",
+        }
+    }
+
+    /// Same as `tone_map` in shaders.wgsl. Maps linear radiance, already scaled by the exposure,
+    /// to linear display values that are in [0, 1] for all operators except `Linear`.
+    pub fn apply(self, colour: [f32; 3]) -> [f32; 3] {
+        let colour = colour.map(|c| c.max(0.));
+        match self {
+            ToneMapping::Linear => colour,
+            ToneMapping::Reinhard => colour.map(|c| c / (1. + c)),
+            ToneMapping::ExtendedReinhard => {
+                let l = luminance(colour);
+                if l <= 0. {
+                    return [0.; 3];
+                }
+                let mapped = l * (1. + l / (EXTENDED_REINHARD_WHITE * EXTENDED_REINHARD_WHITE))
+                    / (1. + l);
+                colour.map(|c| (c * mapped / l).min(1.))
+            }
+            ToneMapping::Aces => colour.map(|c| {
+                let c = 0.6 * c;
+                ((c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14)).clamp(0., 1.)
+            }),
+            ToneMapping::Agx => agx(colour),
+        }
+    }
+}
+
+impl fmt::Display for ToneMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ToneMapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ToneMapping, Error> {
+        match ALL.into_iter().find(|t| t.name() == s) {
+            Some(tone_mapping) => Ok(tone_mapping),
+            None => {
+                let names: Vec<_> = ALL.iter().map(|t| t.name()).collect();
+                bail!("unknown tone mapping {}, expected one of {}", s, names.join(", "))
+            }
+        }
+    }
+}
+
+/// The factor that radiance is scaled by for an exposure of `ev` stops.
+pub fn exposure_scale(ev: f32) -> f32 {
+    ev.exp2()
+}
+
+/// Same as the display transform in `display_fs` in shaders.wgsl, for a target that is neither
+/// sRGB nor floating point: tone mapping followed by gamma encoding.
+pub fn display_transform(radiance: [f32; 3], tone_mapping: ToneMapping, ev: f32) -> [u8; 3] {
+    let scale = exposure_scale(ev);
+    tone_mapping
+        .apply(radiance.map(|c| c * scale))
+        .map(|c| (c.clamp(0., 1.).powf(1. / 2.2) * 255. + 0.5) as u8)
+}
+
+// Rec. 709 luminance.
+fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+// Multiplies by a 3x3 matrix given in column major order, like WGSL's `mat3x3f` constructor.
+fn mul_matrix(m: &[f32; 9], v: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|i| m[i] * v[0] + m[3 + i] * v[1] + m[6 + i] * v[2])
+}
+
+// Same as `agx` in shaders.wgsl.
+// See https://iolite-engine.com/blog_posts/minimal_agx_implementation
+fn agx(colour: [f32; 3]) -> [f32; 3] {
+    // The matrices are copied digit for digit from the reference implementation.
+    #[allow(clippy::excessive_precision)]
+    const INSET: [f32; 9] = [
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104,
+    ];
+    #[allow(clippy::excessive_precision)]
+    const OUTSET: [f32; 9] = [
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116,
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let encoded = mul_matrix(&INSET, colour).map(|c| {
+        let x = (c.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        // Sixth order polynomial fit of the default AgX contrast curve.
+        let (x2, x4) = (x * x, x * x * x * x);
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+    // The curve produces display encoded values, which are linearized for the display transform.
+    mul_matrix(&OUTSET, encoded).map(|c| c.clamp(0., 1.).powf(2.2))
+}
@@ -0,0 +1,300 @@
+//! Statistical and consistency checks of the CPU-side rendering code, run with `--validate`.
+
+use {
+    crate::{
+        bsdf,
+        environment,
+        sampler::{self, PixelSampler, Sampler},
+        sampling::{self, Rng},
+        sky::Sky,
+    },
+    anyhow::{bail, Result},
+    std::f32::consts::PI,
+};
+
+/// Runs all checks and fails on the first one that does not pass.
+pub fn run() -> Result<()> {
+    check_lambertian_furnace()?;
+    check_hdr_decoding()?;
+    check_sky()?;
+    check_blue_noise()?;
+    check_sampler_convergence()?;
+    println!("all checks passed");
+    Ok(())
+}
+
+// Monte Carlo estimate of the expected value of `f`, returned with its standard error.
+fn estimate(samples: u32, mut f: impl FnMut() -> f32) -> (f32, f32) {
+    let (mut sum, mut sum_squared) = (0f64, 0f64);
+    for _ in 0..samples {
+        let x = f() as f64;
+        sum += x;
+        sum_squared += x * x;
+    }
+    let n = samples as f64;
+    let mean = sum / n;
+    let variance = (sum_squared / n - mean * mean).max(0.);
+    (mean as f32, (variance / n).sqrt() as f32)
+}
+
+// Fails if an estimate is further from the expected value than its noise can explain.
+fn check_estimate(name: &str, (mean, standard_error): (f32, f32), expected: f32) -> Result<()> {
+    let tolerance = 4. * standard_error + 1e-3;
+    if (mean - expected).abs() > tolerance {
+        bail!("{}: expected {} but estimated {} (+- {})", name, expected, mean, standard_error);
+    }
+    println!("{}: {} (expected {})", name, mean, expected);
+    Ok(())
+}
+
+// A white furnace test: a surface with albedo 1 lit by a uniform environment of radiance 1 must
+// reflect exactly radiance 1, and an albedo below 1 must scale it accordingly. The BRDF is
+// integrated with uniform sphere samples so that its normalization is checked independently of
+// the importance sampling routine, whose weights are the albedo by construction.
+fn check_lambertian_furnace() -> Result<()> {
+    const SAMPLES: u32 = 200_000;
+
+    let mut rng = Rng::new(2);
+    for albedo in [1., 0.5] {
+        let normal = sampling::sample_sphere(&mut rng);
+        let mut rng = Rng::new(3);
+        check_estimate(
+            &format!("lambertian furnace, albedo {}", albedo),
+            estimate(SAMPLES, || {
+                let wi = sampling::sample_sphere(&mut rng);
+                let cos_theta = normal.dot(&wi).max(0.);
+                bsdf::lambertian_eval(albedo, &normal, &wi) * cos_theta * 4. * PI
+            }),
+            albedo,
+        )?;
+    }
+
+    // Cosine distributed directions have a mean cosine of 2/3, where uniform ones have 1/2.
+    let normal = sampling::sample_sphere(&mut rng);
+    check_estimate(
+        "lambertian mean sampled cosine",
+        estimate(SAMPLES, || {
+            normal.dot(&bsdf::lambertian_sample(1., &normal, &mut rng).direction)
+        }),
+        2. / 3.,
+    )?;
+
+    // The sampling density must integrate to one, match the density and weight reported by
+    // sampling and only produce directions above the surface.
+    check_estimate(
+        "lambertian pdf integral",
+        estimate(SAMPLES, || {
+            bsdf::lambertian_pdf(&normal, &sampling::sample_sphere(&mut rng)) * 4. * PI
+        }),
+        1.,
+    )?;
+    for _ in 0..SAMPLES {
+        let sample = bsdf::lambertian_sample(1., &normal, &mut rng);
+        if normal.dot(&sample.direction) < -1e-6 {
+            bail!("lambertian: sampled {:?} below the surface {:?}", sample.direction, normal);
+        }
+        let pdf = bsdf::lambertian_pdf(&normal, &sample.direction);
+        if (sample.pdf - pdf).abs() > 1e-4 * pdf {
+            bail!("lambertian: sampled with pdf {} but the pdf is {}", sample.pdf, pdf);
+        }
+        let cos_theta = normal.dot(&sample.direction);
+        let weight = bsdf::lambertian_eval(1., &normal, &sample.direction) * cos_theta / pdf;
+        if (sample.weight - weight).abs() > 1e-4 * weight {
+            bail!("lambertian: sampled with weight {} but expected {}", sample.weight, weight);
+        }
+    }
+    Ok(())
+}
+
+// Decodes a small Radiance image with one run length encoded and one flat scanline, and compares
+// it with the pixels it was written from.
+fn check_hdr_decoding() -> Result<()> {
+    let mut file = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 8\n".to_vec();
+    // The red and exponent components are runs, green is literal values, and blue is both.
+    file.extend([2, 2, 0, 8]);
+    file.extend([128 + 8, 200]);
+    file.extend([8, 0, 10, 20, 30, 40, 50, 60, 70]);
+    file.extend([128 + 5, 7, 3, 1, 2, 3]);
+    file.extend([128 + 8, 130]);
+    let rle_pixels = (0..8).map(|x| [200, 10 * x, if x < 5 { 7 } else { x - 4 }, 130]);
+    let flat_pixels: Vec<[u8; 4]> = (0..8).map(|x| [x, 2 * x, 3 * x, 120 + x]).collect();
+    file.extend(flat_pixels.iter().flatten());
+
+    let map = environment::read_hdr(&file[..])?;
+    for (i, rgbe) in rle_pixels.chain(flat_pixels).enumerate() {
+        let scale = (rgbe[3] as f32 - 136.).exp2();
+        let expected = rgbe.map(|c| (c as f32 + 0.5) * scale);
+        let texel = map.texels[i];
+        if (0..3).any(|c| texel[c] != expected[c]) {
+            bail!("hdr: texel {} decoded to {:?} instead of {:?}", i, texel, expected);
+        }
+    }
+    println!("hdr: decoded {} texels of run length encoded and flat scanlines", map.texels.len());
+    Ok(())
+}
+
+// The sky must be finite and non-negative everywhere over the range of turbidities and positions of
+// the sun, including below the horizon. At the equinox the sun rises due east, culminates due south
+// at 90 degrees minus the latitude six hours later, and is back where it started after a day.
+fn check_sky() -> Result<()> {
+    const DIRECTIONS: u32 = 1000;
+
+    let mut rng = Rng::new(8);
+    for turbidity in [2., 3., 6., 10.] {
+        for elevation in [-10., -3., 0., 5., 30., 60., 90.] {
+            let sky = Sky::new(turbidity, elevation, rng.range(0., 360.), 40.);
+            for _ in 0..DIRECTIONS {
+                let direction = sampling::sample_sphere(&mut rng);
+                let radiance = sky.radiance(direction);
+                if radiance.iter().any(|c| !c.is_finite() || *c < 0.) {
+                    bail!(
+                        "sky: radiance {:?} towards {:?} with turbidity {} and the sun at {}",
+                        radiance,
+                        direction,
+                        turbidity,
+                        elevation
+                    );
+                }
+            }
+        }
+    }
+
+    let latitude = 40.;
+    let mut sky = Sky::new(3., 0., 90., latitude);
+    sky.advance(6.);
+    let noon = (sky.sun_elevation(), sky.sun_azimuth());
+    if (noon.0 - (90. - latitude)).abs() > 0.01 || (noon.1 - 180.).abs() > 0.01 {
+        bail!("sky: the sun rose in the east and was at {:?} at noon", noon);
+    }
+    sky.advance(18.);
+    let sunrise = (sky.sun_elevation(), sky.sun_azimuth());
+    if sunrise.0.abs() > 0.01 || (sunrise.1 - 90.).abs() > 0.01 {
+        bail!("sky: the sun rose at (0, 90) but was at {:?} a day later", sunrise);
+    }
+    println!("sky: radiance is finite and the sun follows its daily arc");
+    Ok(())
+}
+
+// Every channel of the blue noise texture must hold each rank once, and the texels of the lowest
+// ranks must be spread out, where random texels would often be next to each other.
+fn check_blue_noise() -> Result<()> {
+    const SIZE: i32 = sampler::BLUE_NOISE_SIZE as i32;
+    const LOWEST: u32 = 256;
+
+    let texture = sampler::blue_noise_texture();
+    let rank_shift = 32 - texture.len().trailing_zeros();
+    for channel in 0..2 {
+        let ranks: Vec<u32> = texture.iter().map(|texel| texel[channel] >> rank_shift).collect();
+        let mut sorted = ranks.clone();
+        sorted.sort_unstable();
+        if sorted.iter().enumerate().any(|(i, &rank)| rank != i as u32) {
+            bail!("blue noise: channel {} does not hold every rank once", channel);
+        }
+
+        let lowest: Vec<(i32, i32)> = (0..SIZE * SIZE)
+            .filter(|&i| ranks[i as usize] < LOWEST)
+            .map(|i| (i % SIZE, i / SIZE))
+            .collect();
+        let mut closest = f32::INFINITY;
+        for (i, a) in lowest.iter().enumerate() {
+            for b in &lowest[..i] {
+                // The texture tiles the image, so distances wrap around its edges.
+                let dx = (a.0 - b.0).rem_euclid(SIZE).min((b.0 - a.0).rem_euclid(SIZE));
+                let dy = (a.1 - b.1).rem_euclid(SIZE).min((b.1 - a.1).rem_euclid(SIZE));
+                closest = closest.min(((dx * dx + dy * dy) as f32).sqrt());
+            }
+        }
+        if closest < 2. {
+            bail!("blue noise: texels of the {} lowest ranks are {} apart", LOWEST, closest);
+        }
+        println!(
+            "blue noise: channel {} is a permutation, the {} lowest ranks are {:.2} texels apart",
+            channel, LOWEST, closest
+        );
+    }
+    Ok(())
+}
+
+// Compares the samplers on integrals with known values, estimated separately in each pixel of a
+// tile like the path tracer does. Every sampler must converge to the right value, and the low
+// discrepancy ones must reach a clearly lower RMSE than random numbers. The integrands are an edge
+// through the pixel, like the silhouette of an object, and a smooth function of six dimensions,
+// like a path with a few bounces.
+fn check_sampler_convergence() -> Result<()> {
+    const SIZE: u32 = 16;
+    const SAMPLE_COUNTS: [u32; 3] = [16, 64, 256];
+    // Largest RMSE of the low discrepancy samplers relative to that of random numbers, at the
+    // highest sample count.
+    const MAX_RMSE_RATIO: f64 = 0.75;
+
+    // A function of the numbers of one sample, whose integral over them is known.
+    type Integrand = fn(&mut PixelSampler) -> f32;
+
+    let blue_noise = sampler::blue_noise_texture();
+    let integrands: [(&str, Integrand, f32); 2] = [
+        (
+            "edge",
+            |sampler| {
+                let (x, y) = (sampler.next_f32(), sampler.next_f32());
+                if x * x + y * y < 1. { 1. } else { 0. }
+            },
+            PI / 4.,
+        ),
+        (
+            "smooth 6d",
+            |sampler| (0..6).map(|_| PI / 2. * (PI * sampler.next_f32()).sin()).product(),
+            1.,
+        ),
+    ];
+    for (name, integrand, expected) in integrands {
+        println!("{}: RMSE at {:?} samples per pixel", name, SAMPLE_COUNTS);
+        let mut random_rmse = 0.;
+        for sampler in [Sampler::Random, Sampler::Sobol, Sampler::BlueNoise] {
+            let mut rmse = Vec::new();
+            let mut pixel_estimates = Vec::new();
+            for count in SAMPLE_COUNTS {
+                pixel_estimates = (0..SIZE * SIZE)
+                    .map(|i| {
+                        let pixel = [i % SIZE, i / SIZE];
+                        let sum: f64 = (0..count)
+                            .map(|index| {
+                                let mut samples =
+                                    PixelSampler::new(sampler, pixel, SIZE, index, &blue_noise);
+                                integrand(&mut samples) as f64
+                            })
+                            .sum();
+                        sum / count as f64
+                    })
+                    .collect();
+                let squared_error: f64 =
+                    pixel_estimates.iter().map(|e| (e - expected as f64).powi(2)).sum();
+                rmse.push((squared_error / pixel_estimates.len() as f64).sqrt());
+            }
+            let row: Vec<_> = rmse.iter().map(|e| format!("{:.5}", e)).collect();
+            println!("  {:10} {}", sampler.to_string(), row.join(" "));
+
+            // The pixels are independent estimates, so their average is within a few standard
+            // errors of the integral if the sampler is unbiased.
+            let mut pixels = pixel_estimates.iter().map(|&e| e as f32);
+            check_estimate(
+                &format!("{} {}: mean of {} pixels", name, sampler, SIZE * SIZE),
+                estimate(SIZE * SIZE, || pixels.next().unwrap()),
+                expected,
+            )?;
+
+            let last = rmse[rmse.len() - 1];
+            match sampler {
+                Sampler::Random => random_rmse = last,
+                _ if last > MAX_RMSE_RATIO * random_rmse => bail!(
+                    "{}: {} has an RMSE of {} but random numbers {}",
+                    name,
+                    sampler,
+                    last,
+                    random_rmse
+                ),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}